@@ -0,0 +1,622 @@
+use std::{
+    f64::consts::PI,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use poise::ChoiceParameter;
+use serde::{Deserialize, Serialize};
+
+use crate::pcm::{Biquad, Processor};
+
+/// Centre frequencies of the equalizer bands, in Hz.
+pub const EQ_BANDS: [f64; 10] = [
+    32.0, 64.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+pub const MAX_EQ_GAIN_DB: f64 = 12.0;
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 2.0;
+/// In semitones, up or down.
+pub const MAX_PITCH: f64 = 12.0;
+const EQ_Q: f64 = 1.41;
+const BASS_BOOST_HZ: f64 = 110.0;
+const BASS_BOOST_DB: f64 = 9.0;
+/// One full turn around the head every 8 seconds.
+const ROTATION_HZ: f64 = 0.125;
+/// Below this the centre is kept by the vocal cut, so the bass stays.
+const VOCAL_CUT_HZ: f64 = 200.0;
+/// Length of the grains of the pitch shifter.
+const PITCH_WINDOW_SECS: f64 = 0.05;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterPreset {
+    #[name = "bassboost"]
+    BassBoost,
+    /// Faster and higher.
+    #[name = "nightcore"]
+    Nightcore,
+    /// Slower and lower.
+    #[name = "vaporwave"]
+    Vaporwave,
+    /// The sound turns around the listener.
+    #[name = "8d"]
+    #[serde(rename = "8d")]
+    EightD,
+    /// Removes what is panned to the centre, usually the vocals.
+    #[name = "karaoke"]
+    Karaoke,
+}
+
+impl fmt::Display for FilterPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterPreset::BassBoost => write!(f, "Bass boost"),
+            FilterPreset::Nightcore => write!(f, "Nightcore"),
+            FilterPreset::Vaporwave => write!(f, "Vaporwave"),
+            FilterPreset::EightD => write!(f, "8D"),
+            FilterPreset::Karaoke => write!(f, "Karaoke"),
+        }
+    }
+}
+
+/// The filters of a guild, kept in its settings until cleared.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSettings {
+    pub preset: Option<FilterPreset>,
+    /// Tempo, the pitch stays the same.
+    pub speed: f64,
+    /// In semitones, the tempo stays the same.
+    pub pitch: f64,
+    /// Gain of every band of `EQ_BANDS`, in dB.
+    pub equalizer: [f64; EQ_BANDS.len()],
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            preset: None,
+            speed: 1.0,
+            pitch: 0.0,
+            equalizer: [0.0; EQ_BANDS.len()],
+        }
+    }
+}
+
+impl FilterSettings {
+    pub fn is_clear(&self) -> bool {
+        *self == FilterSettings::default()
+    }
+
+    /// Brings values edited by hand in the settings file back within what `/filter` allows,
+    /// a speed of 0 would never get through a packet.
+    pub fn sanitize(&mut self) {
+        let within = |value: f64, min: f64, max: f64, default: f64| {
+            if value.is_finite() {
+                value.clamp(min, max)
+            } else {
+                default
+            }
+        };
+        self.speed = within(self.speed, MIN_SPEED, MAX_SPEED, 1.0);
+        self.pitch = within(self.pitch, -MAX_PITCH, MAX_PITCH, 0.0);
+        for gain in &mut self.equalizer {
+            *gain = within(*gain, -MAX_EQ_GAIN_DB, MAX_EQ_GAIN_DB, 0.0);
+        }
+    }
+
+    /// One line per active filter, for the replies of `/filter`.
+    pub fn describe(&self) -> String {
+        if self.is_clear() {
+            return String::from("*No filters.*");
+        }
+
+        let mut lines = Vec::new();
+        if let Some(preset) = self.preset {
+            lines.push(format!("*Preset:* **{}**", preset));
+        }
+        if self.speed != 1.0 {
+            lines.push(format!("*Speed:* **{}x**", self.speed));
+        }
+        if self.pitch != 0.0 {
+            lines.push(format!("*Pitch:* **{:+} semitones**", self.pitch));
+        }
+        let bands = EQ_BANDS
+            .iter()
+            .zip(self.equalizer)
+            .filter(|(_, gain)| *gain != 0.0)
+            .map(|(frequency, gain)| format!("{} {:+}dB", format_frequency(*frequency), gain))
+            .collect::<Vec<_>>();
+        if !bands.is_empty() {
+            lines.push(format!("*Equalizer:* **{}**", bands.join(", ")));
+        }
+        lines.join("\n")
+    }
+
+    /// How much faster the audio is resampled, changing its tempo and pitch together.
+    pub fn resample_rate(&self) -> f64 {
+        let preset = match self.preset {
+            Some(FilterPreset::Nightcore) => 1.25,
+            Some(FilterPreset::Vaporwave) => 0.8,
+            _ => 1.0,
+        };
+        preset * self.speed
+    }
+
+    /// The pitch shift left once resampling changed it along with the tempo.
+    fn pitch_ratio(&self) -> f64 {
+        2f64.powf(self.pitch / 12.0) / self.speed
+    }
+}
+
+fn format_frequency(frequency: f64) -> String {
+    if frequency >= 1000.0 {
+        format!("{}kHz", frequency / 1000.0)
+    } else {
+        format!("{}Hz", frequency)
+    }
+}
+
+/// The filters of a guild, shared with every stream playing so changes apply mid-track.
+#[derive(Clone)]
+pub struct Filters {
+    settings: Arc<Mutex<FilterSettings>>,
+}
+
+impl Filters {
+    pub fn new(settings: FilterSettings) -> Self {
+        Filters {
+            settings: Arc::new(Mutex::new(settings)),
+        }
+    }
+
+    pub fn get(&self) -> FilterSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, settings: FilterSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+}
+
+/// Runs the filters of a guild. A change retunes the stages already running, so the
+/// audio goes on without a click, stages are only created or dropped when turned on or off.
+pub struct FilterChain {
+    filters: Filters,
+    applied: FilterSettings,
+    /// Sample rate and channels the stages were made for.
+    format: Option<(u32, usize)>,
+    equalizer: Option<Equalizer>,
+    vocal_cut: Option<VocalCut>,
+    rotation: Option<Rotation>,
+    pitch_shift: Option<PitchShift>,
+    resampler: Option<Resampler>,
+}
+
+impl FilterChain {
+    pub fn new(filters: Filters) -> Self {
+        FilterChain {
+            filters,
+            applied: FilterSettings::default(),
+            format: None,
+            equalizer: None,
+            vocal_cut: None,
+            rotation: None,
+            pitch_shift: None,
+            resampler: None,
+        }
+    }
+
+    /// Brings the stages in line with `settings`.
+    fn tune(&mut self, settings: &FilterSettings, sample_rate: u32, channels: usize) {
+        let nyquist = f64::from(sample_rate) / 2.0;
+
+        // One slot per band and one for the bass boost, flat ones are skipped.
+        let mut bands = EQ_BANDS
+            .iter()
+            .zip(settings.equalizer)
+            .map(|(frequency, gain)| {
+                (gain != 0.0 && *frequency < nyquist)
+                    .then(|| Biquad::peaking(sample_rate, *frequency, EQ_Q, gain))
+            })
+            .collect::<Vec<_>>();
+        bands.push(
+            (settings.preset == Some(FilterPreset::BassBoost))
+                .then(|| Biquad::low_shelf(sample_rate, BASS_BOOST_HZ, BASS_BOOST_DB)),
+        );
+        let equalizer = keep(
+            &mut self.equalizer,
+            bands.iter().any(Option::is_some),
+            || Equalizer {
+                channels: vec![vec![None; bands.len()]; channels],
+            },
+        );
+        if let Some(equalizer) = equalizer {
+            equalizer.tune(&bands);
+        }
+
+        // Both need two channels to work with.
+        let stereo = channels == 2;
+        keep(
+            &mut self.vocal_cut,
+            stereo && settings.preset == Some(FilterPreset::Karaoke),
+            || VocalCut {
+                low_pass: Biquad::low_pass(sample_rate, VOCAL_CUT_HZ, 0.707),
+            },
+        );
+        keep(
+            &mut self.rotation,
+            stereo && settings.preset == Some(FilterPreset::EightD),
+            || Rotation { phase: 0.0 },
+        );
+
+        let pitch = settings.pitch_ratio();
+        let pitch_shift = keep(
+            &mut self.pitch_shift,
+            (pitch - 1.0).abs() > f64::EPSILON,
+            || PitchShift::new(pitch, sample_rate, channels),
+        );
+        if let Some(pitch_shift) = pitch_shift {
+            pitch_shift.ratio = pitch;
+        }
+
+        let rate = settings.resample_rate();
+        let resampler = keep(
+            &mut self.resampler,
+            (rate - 1.0).abs() > f64::EPSILON,
+            || Resampler {
+                rate,
+                position: 1.0,
+                previous: vec![0.0; channels],
+            },
+        );
+        if let Some(resampler) = resampler {
+            resampler.rate = rate;
+        }
+    }
+}
+
+/// Keeps `stage` while it is `wanted`, made with `new` when it was off.
+fn keep<T>(stage: &mut Option<T>, wanted: bool, new: impl FnOnce() -> T) -> Option<&mut T> {
+    if wanted {
+        Some(stage.get_or_insert_with(new))
+    } else {
+        *stage = None;
+        None
+    }
+}
+
+impl Processor for FilterChain {
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, sample_rate: u32) {
+        let settings = self.filters.get();
+        if self.format != Some((sample_rate, channels)) {
+            *self = FilterChain::new(self.filters.clone());
+            self.format = Some((sample_rate, channels));
+            self.tune(&settings, sample_rate, channels);
+            self.applied = settings;
+        } else if settings != self.applied {
+            self.tune(&settings, sample_rate, channels);
+            self.applied = settings;
+        }
+
+        let stages: [Option<&mut dyn Processor>; 5] = [
+            self.equalizer
+                .as_mut()
+                .map(|stage| stage as &mut dyn Processor),
+            self.vocal_cut
+                .as_mut()
+                .map(|stage| stage as &mut dyn Processor),
+            self.rotation
+                .as_mut()
+                .map(|stage| stage as &mut dyn Processor),
+            self.pitch_shift
+                .as_mut()
+                .map(|stage| stage as &mut dyn Processor),
+            self.resampler
+                .as_mut()
+                .map(|stage| stage as &mut dyn Processor),
+        ];
+        for stage in stages.into_iter().flatten() {
+            stage.process(samples, channels, sample_rate);
+        }
+    }
+
+    fn reset(&mut self) {
+        // Made again on the next packet, without the state of the audio before the seek.
+        self.format = None;
+    }
+
+    fn rate(&self) -> f64 {
+        self.applied.resample_rate()
+    }
+}
+
+struct Equalizer {
+    /// The same filters for every channel, each with its own state.
+    channels: Vec<Vec<Option<Biquad>>>,
+}
+
+impl Equalizer {
+    /// Retunes the filters that stay on, the others are made or dropped.
+    fn tune(&mut self, bands: &[Option<Biquad>]) {
+        for filters in &mut self.channels {
+            for (filter, band) in filters.iter_mut().zip(bands) {
+                match (filter.as_mut(), band) {
+                    (Some(filter), Some(band)) => filter.retune(band),
+                    (_, band) => *filter = *band,
+                }
+            }
+        }
+    }
+}
+
+impl Processor for Equalizer {
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, _sample_rate: u32) {
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, filters) in frame.iter_mut().zip(&mut self.channels) {
+                let mut value = f64::from(*sample);
+                for filter in filters.iter_mut().flatten() {
+                    value = filter.process(value);
+                }
+                *sample = value as f32;
+            }
+        }
+    }
+}
+
+/// Keeps the difference between the channels and the bass of what they share.
+struct VocalCut {
+    low_pass: Biquad,
+}
+
+impl Processor for VocalCut {
+    fn process(&mut self, samples: &mut Vec<f32>, _channels: usize, _sample_rate: u32) {
+        for frame in samples.chunks_exact_mut(2) {
+            let (left, right) = (f64::from(frame[0]), f64::from(frame[1]));
+            let bass = self.low_pass.process((left + right) / 2.0);
+            let side = (left - right) / 2.0;
+            frame[0] = (bass + side) as f32;
+            frame[1] = (bass - side) as f32;
+        }
+    }
+}
+
+/// Pans the audio slowly from one side to the other.
+struct Rotation {
+    phase: f64,
+}
+
+impl Processor for Rotation {
+    fn process(&mut self, samples: &mut Vec<f32>, _channels: usize, sample_rate: u32) {
+        let step = 2.0 * PI * ROTATION_HZ / f64::from(sample_rate);
+        for frame in samples.chunks_exact_mut(2) {
+            let pan = self.phase.sin();
+            // Equal power, both sides at the original level in the centre.
+            let (left, right) = (((1.0 - pan) / 2.0).sqrt(), ((1.0 + pan) / 2.0).sqrt());
+            let mono = (f64::from(frame[0]) + f64::from(frame[1])) / 2.0;
+            frame[0] = (mono * left * 2f64.sqrt()) as f32;
+            frame[1] = (mono * right * 2f64.sqrt()) as f32;
+            self.phase = (self.phase + step) % (2.0 * PI);
+        }
+    }
+}
+
+/// Changes the pitch without changing the tempo, reading a short delay line at another
+/// speed with two crossfaded taps.
+struct PitchShift {
+    ratio: f64,
+    window: usize,
+    /// One delay line per channel.
+    lines: Vec<Vec<f32>>,
+    written: usize,
+    /// Delay of the first tap, the second one is half a window away.
+    delay: f64,
+}
+
+impl PitchShift {
+    fn new(ratio: f64, sample_rate: u32, channels: usize) -> Self {
+        let window = ((f64::from(sample_rate) * PITCH_WINDOW_SECS) as usize).max(4);
+        PitchShift {
+            ratio,
+            window,
+            lines: vec![vec![0.0; window + 2]; channels],
+            written: 0,
+            delay: 0.0,
+        }
+    }
+
+    fn tap(line: &[f32], written: usize, delay: f64) -> f64 {
+        let len = line.len();
+        let position = written as f64 + len as f64 - delay;
+        let index = position.floor() as usize;
+        let frac = position - position.floor();
+        let (a, b) = (line[index % len], line[(index + 1) % len]);
+        f64::from(a) + (f64::from(b) - f64::from(a)) * frac
+    }
+}
+
+impl Processor for PitchShift {
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, _sample_rate: u32) {
+        let window = self.window as f64;
+        for frame in samples.chunks_exact_mut(channels) {
+            let len = self.lines[0].len();
+            for (sample, line) in frame.iter().zip(&mut self.lines) {
+                line[self.written % len] = *sample;
+            }
+
+            let second = (self.delay + window / 2.0) % window;
+            // Triangular windows, silent where a tap jumps back.
+            let weight = |delay: f64| 1.0 - (2.0 * delay / window - 1.0).abs();
+            for (sample, line) in frame.iter_mut().zip(&self.lines) {
+                let value = PitchShift::tap(line, self.written, self.delay) * weight(self.delay)
+                    + PitchShift::tap(line, self.written, second) * weight(second);
+                *sample = value as f32;
+            }
+
+            self.written = (self.written + 1) % len;
+            self.delay = (self.delay + 1.0 - self.ratio).rem_euclid(window);
+        }
+    }
+}
+
+/// Plays the audio faster or slower by linear interpolation, changing tempo and pitch.
+struct Resampler {
+    rate: f64,
+    /// Where the next frame is read, 0.0 being the last frame of the previous packet.
+    position: f64,
+    previous: Vec<f32>,
+}
+
+impl Processor for Resampler {
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, _sample_rate: u32) {
+        let frames = samples.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        // Frame 0 is the last one of the previous packet.
+        let frame = |index: usize, channel: usize| {
+            if index == 0 {
+                self.previous[channel]
+            } else {
+                samples[(index - 1) * channels + channel]
+            }
+        };
+
+        let mut output = Vec::with_capacity((frames as f64 / self.rate) as usize * channels + 2);
+        while self.position < frames as f64 {
+            let index = self.position.floor() as usize;
+            let frac = (self.position - self.position.floor()) as f32;
+            for channel in 0..channels {
+                let (a, b) = (frame(index, channel), frame(index + 1, channel));
+                output.push(a + (b - a) * frac);
+            }
+            self.position += self.rate;
+        }
+
+        self.position -= frames as f64;
+        self.previous
+            .copy_from_slice(&samples[(frames - 1) * channels..frames * channels]);
+        *samples = output;
+    }
+
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+    const PACKET: usize = 960;
+
+    fn tone(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let sample = (n as f32 * 0.05).sin() * 0.5;
+                [sample, -sample]
+            })
+            .collect()
+    }
+
+    fn chain(settings: FilterSettings) -> (Filters, FilterChain) {
+        let filters = Filters::new(settings);
+        (filters.clone(), FilterChain::new(filters))
+    }
+
+    /// Frames given out for `packets` packets of `PACKET` frames.
+    fn frames_out(chain: &mut FilterChain, packets: usize) -> usize {
+        (0..packets)
+            .map(|_| {
+                let mut samples = tone(PACKET);
+                chain.process(&mut samples, 2, RATE);
+                samples.len() / 2
+            })
+            .sum()
+    }
+
+    #[test]
+    fn unity_settings_pass_audio_through() {
+        let (_, mut chain) = chain(FilterSettings::default());
+        let mut samples = tone(PACKET);
+        chain.process(&mut samples, 2, RATE);
+
+        assert_eq!(samples, tone(PACKET));
+        assert!(chain.equalizer.is_none() && chain.pitch_shift.is_none());
+        assert!(chain.resampler.is_none());
+    }
+
+    #[test]
+    fn speed_scales_the_frame_count() {
+        for (speed, expected) in [(2.0, 5 * PACKET), (0.5, 20 * PACKET), (1.25, 8 * PACKET)] {
+            let (_, mut chain) = chain(FilterSettings {
+                speed,
+                ..FilterSettings::default()
+            });
+            let frames = frames_out(&mut chain, 10);
+            // The resampler holds back the last frame of each packet, to read across them.
+            assert!(
+                frames.abs_diff(expected) <= 2,
+                "{speed}x gave {frames} frames"
+            );
+        }
+    }
+
+    #[test]
+    fn changes_retune_the_running_stages() {
+        let (filters, mut chain) = chain(FilterSettings {
+            speed: 1.5,
+            pitch: 2.0,
+            ..FilterSettings::default()
+        });
+        frames_out(&mut chain, 3);
+        let position = chain.resampler.as_ref().unwrap().position;
+        let written = chain.pitch_shift.as_ref().unwrap().written;
+
+        filters.set(FilterSettings {
+            speed: 1.25,
+            pitch: 1.0,
+            ..FilterSettings::default()
+        });
+        let mut empty = Vec::new();
+        chain.process(&mut empty, 2, RATE);
+        let resampler = chain.resampler.as_ref().unwrap();
+        assert_eq!((resampler.rate, resampler.position), (1.25, position));
+        assert_eq!(chain.pitch_shift.as_ref().unwrap().written, written);
+
+        filters.set(FilterSettings::default());
+        chain.process(&mut empty, 2, RATE);
+        assert!(chain.resampler.is_none() && chain.pitch_shift.is_none());
+    }
+
+    #[test]
+    fn sanitize_brings_values_within_range() {
+        let mut settings = FilterSettings {
+            preset: None,
+            speed: 0.0,
+            pitch: f64::NAN,
+            equalizer: [
+                30.0,
+                -30.0,
+                f64::INFINITY,
+                3.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+            ],
+        };
+        settings.sanitize();
+        assert_eq!(settings.speed, MIN_SPEED);
+        assert_eq!(settings.pitch, 0.0);
+        assert_eq!(settings.equalizer[..4], [12.0, -12.0, 0.0, 3.0]);
+
+        settings.speed = f64::NAN;
+        settings.sanitize();
+        assert_eq!(settings.speed, 1.0);
+    }
+}
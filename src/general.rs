@@ -1,90 +1,67 @@
-use std::{
-    collections::HashMap,
-    error::Error,
-    path::PathBuf,
-    process::{Command, Stdio},
-    sync::Arc,
-    time::{Duration, Instant},
-};
-
-use futures::{future::join_all, StreamExt};
+use std::{error::Error, ops::RangeInclusive, path::PathBuf};
 
-use rspotify::{
-    model::{PlayableItem, PlaylistId},
-    prelude::BaseClient,
-    ClientCredsSpotify,
-};
+use futures::{future::join_all, stream, StreamExt};
 
 use serenity::{
-    all::{ChannelId, Context, GuildId, Http, UserId},
-    async_trait,
+    all::{ChannelId, Context, GuildId, UserId},
     builder::GetMessages,
 };
 
 use songbird::{
-    input::{Compose, File, YoutubeDl},
-    tracks::TrackQueue,
-    Event, EventContext, TrackEvent,
+    input::{Compose, File, Input, LiveInput},
+    tracks::Track,
 };
 
-use songbird::EventHandler as VoiceEventHandler;
-
-use tokio::time;
-
 use serde_json::{self, Value};
 
-use crate::{spotify, Data, HttpKey};
-
-use humantime::format_duration;
-use tokio_util::sync::CancellationToken;
-
-const PLAYLIST_LIMIT: Option<usize> = Some(200);
-
-enum MultipleSongs {
-    YtPlaylist(Vec<String>),
-    SpotiPlaylist(Vec<String>),
-    SpotiAlbum(Vec<String>),
-}
-
-struct SongStartNotifier {
-    chan_id: ChannelId,
-    http: Arc<Http>,
-    title: String,
-    video_url: String,
-}
+use crate::{
+    filters::FilterSettings,
+    player::{Placement, PlayerHandle, Prefetch},
+    progress::PlaylistProgress,
+    queue::{format_length, QueuedTrack, TrackSource},
+    resolver::{PendingTrack, Resolution},
+    seek::{SeekError, SeekTarget},
+    settings, Data,
+};
 
-struct RemoveTitleNotifier {
-    guild_id: GuildId,
-    titles: Arc<tokio::sync::Mutex<HashMap<GuildId, (TrackQueue, Vec<String>)>>>,
+/// Part of a playlist or album to enqueue, `offset` is 0-based.
+#[derive(Clone, Copy, Debug)]
+pub struct PlaylistRange {
+    pub offset: usize,
+    pub limit: usize,
+    /// For artist links: every album and single instead of the top tracks.
+    pub discography: bool,
 }
 
-#[async_trait]
-impl VoiceEventHandler for SongStartNotifier {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::Track(_) = ctx {
-            let formatted_message =
-                format!("**Now playing:** [{}]({})", self.title, self.video_url);
-            if let Err(err) = self.chan_id.say(&self.http, formatted_message).await {
-                eprintln!("Failed to send message: {}", err);
-            }
+impl PlaylistRange {
+    /// Builds the range from the 1-based, inclusive `start` and `end` given to `/play`,
+    /// never asking for more than `max_tracks`.
+    pub fn new(start: Option<usize>, end: Option<usize>, max_tracks: usize) -> Self {
+        let offset = start.unwrap_or(1).saturating_sub(1);
+        let wanted = end.map_or(usize::MAX, |end| end.saturating_sub(offset));
+
+        PlaylistRange {
+            offset,
+            limit: wanted.min(max_tracks),
+            discography: false,
         }
-
-        None
     }
-}
 
-#[async_trait]
-impl VoiceEventHandler for RemoveTitleNotifier {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::Track(_) = ctx {
-            let mut map = self.titles.lock().await;
-            let (_, titles) = map.get_mut(&self.guild_id).unwrap();
-            titles.remove(0);
+    pub fn everything() -> Self {
+        PlaylistRange {
+            offset: 0,
+            limit: usize::MAX,
+            discography: false,
         }
-        None
     }
 }
 
+/// Tracks taken from a playlist or album, and how many it has in total.
+pub struct PlaylistPage<T> {
+    pub tracks: Vec<T>,
+    pub total: usize,
+}
+
 pub async fn delete_messages(ctx: &Context, channel_id: &ChannelId) {
     let bot_user_id = ctx.cache.current_user().id;
     let builder = GetMessages::new().limit(10);
@@ -116,12 +93,7 @@ pub async fn join_channel(ctx: &Context, guild_id: &GuildId, author_id: &UserId,
 
     match manager.join(*guild_id, voice_channel_id).await {
         Ok(_) => {
-            {
-                let mut last_interaction = data.last_interaction.lock().await;
-                //let mut last_interaction = data.last_interaction.lock().unwrap();
-                *last_interaction = Instant::now();
-            }
-            start_inactivity_checker(ctx, guild_id, data).await;
+            data.inactivity.touch(*guild_id);
         }
         Err(e) => {
             println!("Failed to join the channel: {:?}", e);
@@ -147,69 +119,49 @@ pub async fn get_user_voice_channel(
     None
 }
 
-async fn start_inactivity_checker(ctx: &Context, guild_id: &GuildId, data: &Data) {
-    let last_interaction = Arc::clone(&data.last_interaction);
-    let tracks_hash_map = Arc::clone(&data.tracks);
-
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation")
-        .clone();
-    let c_guild_id = *guild_id;
-
-    tokio::spawn(async move {
-        let timeout_duration = Duration::from_secs(15 * 60);
-        let mut interval = time::interval(Duration::from_secs(60));
-
-        loop {
-            interval.tick().await;
-            let last_interaction_time = *last_interaction.lock().await;
-            //Here we get the track handle for the current server.
-            let guard = tracks_hash_map.lock().await;
-
-            let mut queued_cnt = 0;
-
-            if let Some((crt_queue, _)) = guard.get(&c_guild_id) {
-                queued_cnt = crt_queue.len();
-            };
-
-            //If no song is playing and the interaction time is over, quit.
-            if last_interaction_time.elapsed() >= timeout_duration && queued_cnt == 0 {
-                let _ = manager.leave(c_guild_id).await;
-                break;
-            }
-        }
-    });
-}
-
-pub async fn play_from_source(ctx: &Context, guild_id: &GuildId, path: PathBuf) {
+/// Plays a soundboard clip over the music, at the soundboard volume of the guild,
+/// through its filters and normalized if the guild wants it.
+pub async fn play_from_source(ctx: &Context, guild_id: &GuildId, path: PathBuf, data: &Data) {
     let manager = songbird::get(ctx)
         .await
         .expect("Songbird Voice client placed in at initialisation")
         .clone();
 
     if let Some(handler_lock) = manager.get(*guild_id) {
+        let player = get_player(ctx, guild_id, data).await;
+        let stream = match File::new(path.clone()).create() {
+            Ok(stream) => player.effects().clip(stream).into_stream(),
+            Err(e) => {
+                eprintln!("Failed to open {:?}: {}", path, e);
+                return;
+            }
+        };
         let mut handler = handler_lock.lock().await;
 
-        let source = File::new(path);
-        let _ = handler.play_input(source.into());
+        let guild_settings = data.settings.get(*guild_id);
+        let gain = match (guild_settings.normalization_target(), path.file_name()) {
+            (Some(target), Some(name)) => data.clip_loudness.gain(&name.to_string_lossy(), target),
+            _ => 1.0,
+        };
+        let volume = settings::gain(guild_settings.soundboard_volume) * gain;
+
+        let input = Input::Live(LiveInput::Raw(stream), None);
+        let handle = handler.play(Track::from(input).volume(volume));
+        data.clips.add(*guild_id, handle, gain, player);
     } else {
         println!("No handler dayum");
     }
 }
 
+/// Stops the music and the soundboard clips, cancelling any playlist being enqueued.
 pub async fn stop_reproduction(ctx: &Context, guild_id: &GuildId, data: &Data) {
     let manager = songbird::get(ctx)
         .await
         .expect("Songbird Voice client placed in at initialisation")
         .clone();
 
-    let guard = data.tracks.lock().await;
-    if let Some(token) = data.playlist_cancellation.lock().await.remove(guild_id) {
-        token.cancel();
-    }
-    if let Some((queue, _)) = guard.get(guild_id) {
-        queue.stop();
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.clear();
     }
 
     if let Some(handler_lock) = manager.get(*guild_id) {
@@ -219,20 +171,21 @@ pub async fn stop_reproduction(ctx: &Context, guild_id: &GuildId, data: &Data) {
     }
 }
 
+/// What `/play` and its variants asked for.
+pub struct SongRequest {
+    pub query: String,
+    pub range: PlaylistRange,
+    pub placement: Placement,
+}
+
 pub async fn play_songs(
     ctx: &Context,
-    url: String,
+    request: SongRequest,
     guild_id: GuildId,
     msg_channel_id: ChannelId,
     author_id: &UserId,
     data: &Data,
 ) {
-    data.tracks
-        .lock()
-        .await
-        .entry(guild_id)
-        .or_insert((TrackQueue::new(), Vec::<String>::new()));
-
     join_channel(ctx, &guild_id, author_id, data).await;
 
     let manager = songbird::get(ctx)
@@ -241,7 +194,7 @@ pub async fn play_songs(
         .clone();
 
     if let Some(_handler_lock) = manager.get(guild_id) {
-        handle_song_request(ctx, url, data, msg_channel_id, &guild_id).await;
+        handle_song_request(ctx, request, data, msg_channel_id, &guild_id, author_id).await;
     } else {
         println!("Not in a channel");
     }
@@ -249,326 +202,436 @@ pub async fn play_songs(
 
 pub async fn handle_song_request(
     ctx: &Context,
-    url: String,
+    request: SongRequest,
     data: &Data,
     msg_channel_id: ChannelId,
     guild_id: &GuildId,
+    author_id: &UserId,
 ) {
-    let http_client = {
-        let data = ctx.data.read().await;
-        data.get::<HttpKey>()
-            .cloned()
-            .expect("Guaranteed to exist in the typemap.")
-    };
-
-    // Check if this is a playlist first
-    match get_multiple_songs(data, url.clone()).await {
-        Some(playlist) => {
-            // Handle playlist
-            let (tracks, _is_youtube) = match playlist {
-                MultipleSongs::YtPlaylist(tracks) => (tracks, true),
-                MultipleSongs::SpotiAlbum(tracks) | MultipleSongs::SpotiPlaylist(tracks) => {
-                    (tracks, false)
-                }
-            };
-
+    let placement = request.placement;
+    match data.resolvers.resolve(&request.query, request.range).await {
+        Err(e) => {
+            eprintln!("Could not resolve {}: {:?}", request.query, e);
+            send_message(&msg_channel_id, ctx, e.to_string()).await;
+        }
+        Ok(Resolution::Playlist(page)) => {
             println!("Playlist handling");
-
-            let token = CancellationToken::new();
-
-            {
-                // Add the cancellation token for the current Guild
-                let cancels = data.playlist_cancellation.lock();
-                cancels.await.insert(*guild_id, token.clone());
-            }
-
-            send_message(
-                &msg_channel_id,
+            let page = PlaylistPage {
+                tracks: page
+                    .tracks
+                    .into_iter()
+                    .map(|track| BatchItem::Track(Box::new(track)))
+                    .collect(),
+                total: page.total,
+            };
+            enqueue_playlist(
                 ctx,
-                format!("**Enqueuing:** [{}] songs.", tracks.len()),
+                page,
+                *author_id,
+                data,
+                msg_channel_id,
+                guild_id,
+                placement,
             )
             .await;
-
-            // Process each track in the playlist
-            for track_url in tracks {
-                if token.is_cancelled() {
-                    break;
-                }
-                process_single_track(
-                    ctx,
-                    track_url,
-                    data,
-                    msg_channel_id,
-                    guild_id,
-                    &http_client,
-                    true,
-                    true,
-                    Some(&token),
-                )
-                .await;
-            }
         }
-        None => {
-            // Handle single song
+        Ok(Resolution::Track(track)) => {
             process_single_track(
                 ctx,
-                url.clone(),
+                *track,
+                *author_id,
                 data,
                 msg_channel_id,
                 guild_id,
-                &http_client,
-                !url.starts_with("http"),
-                false,
-                None,
+                placement,
             )
             .await;
         }
     }
 }
 
-pub async fn seek(
+/// A song of a playlist being enqueued.
+enum BatchItem {
+    Track(Box<PendingTrack>),
+    /// From a saved playlist or a queue file, looked up while enqueuing.
+    Query(String),
+}
+
+/// Enqueues the playlist tracks as placeholders, each one is loaded shortly before it plays.
+/// Queries are looked up a few at a time, a single message shows the progress.
+async fn enqueue_playlist(
     ctx: &Context,
-    guild_id: &GuildId,
-    msg_channel_id: &ChannelId,
+    page: PlaylistPage<BatchItem>,
+    requester: UserId,
     data: &Data,
-    seconds: String,
+    msg_channel_id: ChannelId,
+    guild_id: &GuildId,
+    placement: Placement,
 ) {
-    if let Some((track_handle, _)) = data.tracks.lock().await.get(guild_id) {
-        let current_handle = track_handle.current().unwrap();
-        let seconds_int = seconds.parse().expect("Could not parse number from string");
-
-        let seek_result = current_handle.seek(Duration::from_secs(seconds_int));
-
-        match seek_result.result() {
-            Ok(_) => {
-                send_message(
-                    msg_channel_id,
-                    ctx,
-                    format!(
-                        "Successfully skipped at: **{}**.",
-                        format_duration(Duration::from_secs(seconds_int))
-                    ),
-                )
-                .await
+    let player = get_player(ctx, guild_id, data).await;
+    let token = player.new_batch().await;
+    let label = match placement {
+        Placement::Last => "Enqueued",
+        Placement::Next => "Playing next",
+        Placement::Now { .. } => "Playing now",
+    };
+    let progress = PlaylistProgress::start(
+        ctx.http.clone(),
+        msg_channel_id,
+        label,
+        page.tracks.len(),
+        page.total,
+        token.clone(),
+    )
+    .await;
+
+    // Playlists among the queries are cut like the ones given to `/play`.
+    let range = PlaylistRange::new(None, None, data.settings.get(*guild_id).playlist_limit);
+    let count = page.tracks.len();
+    // `buffered` looks up the next queries while keeping the songs in order.
+    let mut found = stream::iter(page.tracks)
+        .map(|item| async move {
+            match item {
+                BatchItem::Track(track) => Ok(vec![*track]),
+                BatchItem::Query(query) => match data.resolvers.resolve(&query, range).await {
+                    Ok(Resolution::Track(track)) => Ok(vec![*track]),
+                    Ok(Resolution::Playlist(page)) => Ok(page.tracks),
+                    Err(e) => {
+                        eprintln!("Could not resolve {}: {:?}", query, e);
+                        Err(query)
+                    }
+                },
             }
-            Err(e) => {
-                eprintln!("{}", e);
-                send_message(
-                    msg_channel_id,
-                    ctx,
-                    String::from("Seek failed. Only **forward seek** is supported."),
-                )
-                .await
+        })
+        .buffered(data.playlist_concurrency.max(1))
+        .ready_chunks(count.max(1));
+
+    // Tracks go at the end as soon as they are found, so the first one starts right away.
+    // The others wait for the rest to keep their order.
+    let mut held = Vec::new();
+    let mut not_found = Vec::new();
+    loop {
+        let chunk = tokio::select! {
+            _ = token.cancelled() => break,
+            chunk = found.next() => chunk,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        let mut sources = Vec::new();
+        for tracks in chunk {
+            match tracks {
+                Ok(tracks) => {
+                    progress.enqueued(tracks.len());
+                    sources.extend(pending_sources(tracks, requester, &progress));
+                }
+                Err(query) => {
+                    progress.failed();
+                    not_found.push(query);
+                }
             }
         }
+        match placement {
+            Placement::Last => {
+                player.enqueue(sources, msg_channel_id, Some(token.clone()), placement)
+            }
+            Placement::Next | Placement::Now { .. } => held.extend(sources),
+        }
+    }
 
-        let _ = current_handle.play();
+    if token.is_cancelled() {
+        println!("Track addition cancelled.");
+        return;
+    }
+    if !held.is_empty() {
+        player.enqueue(held, msg_channel_id, Some(token), placement);
+    }
+    progress.resolved();
+
+    if !not_found.is_empty() {
+        send_message(&msg_channel_id, ctx, not_found_message(&not_found)).await;
     }
 }
 
-async fn process_single_track(
+/// Lists the queries nothing was found for, the first few by name.
+fn not_found_message(queries: &[String]) -> String {
+    const SHOWN: usize = 10;
+
+    let mut message = String::from("**Not found:**");
+    for query in queries.iter().take(SHOWN) {
+        message.push_str(&format!("\n- {}", query));
+    }
+    if queries.len() > SHOWN {
+        message.push_str(&format!("\n*...and {} more.*", queries.len() - SHOWN));
+    }
+    message
+}
+
+/// Resolves a list of songs, from a saved playlist or an imported file, and enqueues
+/// the first `limit` of them like the tracks of a playlist.
+pub async fn play_queries(
     ctx: &Context,
-    url: String,
-    data: &Data,
+    queries: Vec<String>,
+    limit: usize,
+    guild_id: GuildId,
     msg_channel_id: ChannelId,
-    guild_id: &GuildId,
-    http_client: &reqwest::Client,
-    do_search: bool,
-    is_playlist: bool,
-    token: Option<&CancellationToken>,
+    author_id: &UserId,
+    data: &Data,
 ) {
-    let mut searching = do_search;
-    let url = match spotify::get_spoti_track_id(&url) {
-        Some(track_id) => {
-            searching = true;
-            spotify::get_spoti_track_title(&track_id.to_string(), data).await
-        }
-        None => url,
-    };
+    join_channel(ctx, &guild_id, author_id, data).await;
 
-    let mut src = if searching {
-        YoutubeDl::new_search(http_client.clone(), url)
-    } else {
-        YoutubeDl::new(http_client.clone(), url)
+    let total = queries.len();
+    let page = PlaylistPage {
+        tracks: queries
+            .into_iter()
+            .take(limit)
+            .map(BatchItem::Query)
+            .collect(),
+        total,
     };
+    enqueue_playlist(
+        ctx,
+        page,
+        *author_id,
+        data,
+        msg_channel_id,
+        &guild_id,
+        Placement::Last,
+    )
+    .await;
+}
 
-    let metadata = src.aux_metadata().await;
+/// Placeholders for resolved tracks, loaded once they are about to play,
+/// which `progress` is told about.
+fn pending_sources(
+    tracks: Vec<PendingTrack>,
+    requester: UserId,
+    progress: &PlaylistProgress,
+) -> Vec<(TrackSource, QueuedTrack)> {
+    tracks
+        .into_iter()
+        .map(|track| {
+            let entry = QueuedTrack::from_metadata(&track.metadata, requester, track.kind);
+            let source = TrackSource::deferred(track.loader).with_progress(progress.clone());
+            (source, entry)
+        })
+        .collect()
+}
 
-    if let Ok(aux_metadata) = metadata {
-        let title = aux_metadata.title.as_deref().unwrap_or("Unknown Title");
-        let video_url = aux_metadata
-            .source_url
-            .as_deref()
-            .unwrap_or("URL not available");
+pub async fn seek(
+    ctx: &Context,
+    guild_id: &GuildId,
+    msg_channel_id: &ChannelId,
+    data: &Data,
+    position: String,
+) {
+    let Some(player) = existing_player(guild_id, data).await else {
+        send_message(msg_channel_id, ctx, SeekError::NothingPlaying.to_string()).await;
+        return;
+    };
 
-        let mut guard = data.tracks.lock().await;
+    let result = match position.parse::<SeekTarget>() {
+        Ok(target) => player.seek(target, *msg_channel_id).await,
+        Err(e) => Err(e),
+    };
 
-        if let Some(token) = token {
-            if token.is_cancelled() {
-                println!("Track addition cancelled.");
-                return;
-            }
+    match result {
+        Ok(position) => {
+            send_message(
+                msg_channel_id,
+                ctx,
+                format!("Moved to **{}**.", format_length(position)),
+            )
+            .await
         }
+        Err(e) => {
+            eprintln!("Seek to '{}' failed: {:?}", position, e);
+            send_message(msg_channel_id, ctx, e.to_string()).await
+        }
+    }
+}
 
-        if let Some((queue, titles)) = guard.get_mut(&guild_id) {
-            if !is_playlist && !queue.is_empty() {
+async fn process_single_track(
+    ctx: &Context,
+    track: PendingTrack,
+    requester: UserId,
+    data: &Data,
+    msg_channel_id: ChannelId,
+    guild_id: &GuildId,
+    placement: Placement,
+) {
+    match track.loader.load().await {
+        Ok(loaded) => {
+            let entry = QueuedTrack::from_metadata(&loaded.metadata, requester, track.kind);
+            let player = get_player(ctx, guild_id, data).await;
+
+            // The now playing message covers tracks that start right away.
+            let label = match placement {
+                Placement::Last => "Added to the queue",
+                Placement::Next => "Playing next",
+                Placement::Now { .. } => "",
+            };
+            if !label.is_empty() && !player.snapshot().is_empty() {
                 send_message(
                     &msg_channel_id,
                     ctx,
-                    format!("**Added to the queue:** [{}]({})", title, video_url),
+                    format!(
+                        "**{}:** [{}]({})",
+                        label,
+                        entry.title,
+                        entry.url_or_placeholder()
+                    ),
                 )
                 .await;
             }
-            titles.push(title.to_string());
-
-            let manager = songbird::get(ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialisation")
-                .clone();
-
-            if let Some(handler_lock) = manager.get(*guild_id) {
-                let mut handler = handler_lock.lock().await;
-                let track_handler = queue.add_source(src.clone().into(), &mut *handler).await;
-                let send_http = ctx.http.clone();
-
-                let _ = track_handler.add_event(
-                    Event::Track(TrackEvent::Play),
-                    SongStartNotifier {
-                        chan_id: msg_channel_id,
-                        http: send_http,
-                        title: title.to_string(),
-                        video_url: video_url.to_string(),
-                    },
-                );
-
-                let _ = track_handler.add_event(
-                    Event::Track(TrackEvent::End),
-                    RemoveTitleNotifier {
-                        guild_id: *guild_id,
-                        titles: data.tracks.clone(),
-                    },
-                );
-            }
+
+            player.enqueue(
+                vec![(TrackSource::loaded(track.loader, loaded.input), entry)],
+                msg_channel_id,
+                None,
+                placement,
+            );
+        }
+        Err(e) => {
+            eprintln!("Failed to load track: {:?}", e);
+            send_message(&msg_channel_id, ctx, e.to_string()).await;
         }
-    } else {
-        println!("Failed to fetch aux metadata: {:?}", metadata.unwrap_err());
     }
 }
 
-pub async fn clear(guild_id: &GuildId, data: &Data) {
-    let guard = data.tracks.lock().await;
-    if let Some((queue, _)) = guard.get(&guild_id) {
-        if let Some(token) = data.playlist_cancellation.lock().await.remove(guild_id) {
-            token.cancel();
+/// Parses `3` or `3-10` into an inclusive range of queue indexes.
+pub fn parse_range(input: &str) -> Option<RangeInclusive<usize>> {
+    let (start, end) = match input.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let index = input.trim().parse().ok()?;
+            (index, index)
         }
-        queue.stop();
-    }
+    };
+    (start <= end).then_some(start..=end)
 }
 
-pub async fn skip_song(guild_id: &GuildId, data: &Data) {
-    if let Some((queue, _)) = data.tracks.lock().await.get(guild_id) {
-        let _ = queue.skip();
+/// The first tracks of the queue, numbered like `/list` does.
+pub fn queue_summary(entries: &[QueuedTrack]) -> String {
+    const SHOWN: usize = 10;
+
+    if entries.is_empty() {
+        return String::from("*No songs in queue.*");
     }
-}
 
-pub async fn pause_song(guild_id: &GuildId, data: &Data) {
-    if let Some((queue, _)) = data.tracks.lock().await.get(guild_id) {
-        let _ = queue.pause();
+    let mut summary = String::new();
+    for (index, entry) in entries.iter().enumerate().take(SHOWN) {
+        if index == 0 {
+            summary.push_str(&format!(
+                "`{:>2}.`*__ Now playing__:* {}\n",
+                index,
+                entry.describe()
+            ));
+        } else {
+            summary.push_str(&format!("`{:>2}.`{}\n", index, entry.describe()));
+        }
+    }
+    if entries.len() > SHOWN {
+        summary.push_str(&format!("*...and {} more.*", entries.len() - SHOWN));
     }
+    summary
 }
 
-pub async fn resume_song(guild_id: &GuildId, data: &Data) {
-    if let Some((queue, _)) = data.tracks.lock().await.get(guild_id) {
-        let _ = queue.resume();
+/// Returns the player of the guild, spawning it on first use.
+pub async fn get_player(ctx: &Context, guild_id: &GuildId, data: &Data) -> PlayerHandle {
+    let mut players = data.players.lock().await;
+    if let Some(player) = players.get(guild_id) {
+        return player.clone();
     }
+
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialisation")
+        .clone();
+    let player = PlayerHandle::spawn(
+        *guild_id,
+        manager,
+        ctx.http.clone(),
+        ctx.shard.clone(),
+        data.settings.clone(),
+        data.history.clone(),
+        Prefetch {
+            lookahead: data.lookahead,
+            concurrency: data.playlist_concurrency,
+        },
+    );
+    players.insert(*guild_id, player.clone());
+    player
 }
 
-async fn get_urls_playlist(
-    url: String,
-    limit: Option<usize>,
-    spotify_client: &ClientCredsSpotify,
-) -> Vec<String> {
-    // url as a name of the parameter is mis leading, in this case it is parsed as an id already!
-    match PlaylistId::from_id(&url) {
-        Ok(playlist_id) => {
-            println!("Spotify playlist found: {:?}", playlist_id);
-            let mut urls = Vec::new();
-            let mut items = spotify_client.playlist_items(playlist_id, None, None);
-
-            while let Some(url) = items.next().await {
-                match url {
-                    Ok(playlist_item) => {
-                        if let Some(PlayableItem::Track(track)) = playlist_item.track {
-                            urls.push(track.name + " " + &track.artists[0].name);
-                        }
-                    }
-                    Err(e) => {
-                        println!("{:?}", e);
-                    }
-                }
-            }
+pub async fn existing_player(guild_id: &GuildId, data: &Data) -> Option<PlayerHandle> {
+    data.players.lock().await.get(guild_id).cloned()
+}
 
-            urls
+/// Sets the music volume, or the soundboard one, in percent.
+pub async fn set_volume(guild_id: &GuildId, data: &Data, percent: u16, soundboard: bool) {
+    data.settings.update(*guild_id, |settings| {
+        if soundboard {
+            settings.soundboard_volume = percent;
+        } else {
+            settings.music_volume = percent;
         }
-        Err(e) => {
-            println!("Error on parsing spotify: {:?}", e);
-            let mut result = Vec::<String>::new();
-
-            let mut cmd = Command::new("yt-dlp");
-            cmd.arg("-j").arg("--flat-playlist").arg(&url);
+    });
 
-            // Add yt-dlp limit if specified
-            if let Some(max_items) = limit {
-                cmd.arg("--playlist-end").arg(max_items.to_string());
-            }
+    if soundboard {
+        data.clips.set_volume(*guild_id, settings::gain(percent));
+    } else if let Some(player) = existing_player(guild_id, data).await {
+        player.set_volume(percent);
+    }
+}
 
-            let output = cmd
-                .stdout(Stdio::piped())
-                .output()
-                .expect("Failed to execute yt-dlp command");
+pub async fn set_crossfade(guild_id: &GuildId, data: &Data, secs: u64) {
+    data.settings
+        .update(*guild_id, |settings| settings.crossfade_secs = secs);
 
-            let output_str = std::str::from_utf8(&output.stdout).expect("Invalid UTF-8 output");
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.set_crossfade(secs);
+    }
+}
 
-            for line in output_str.lines() {
-                // Double-check limit in case yt-dlp didn't respect it
-                if let Some(max_items) = limit {
-                    if result.len() >= max_items {
-                        break;
-                    }
-                }
+/// Changes the filters of the guild, applied right away to what is playing. Returns them.
+pub async fn change_filters(
+    guild_id: &GuildId,
+    data: &Data,
+    change: impl FnOnce(&mut FilterSettings),
+) -> FilterSettings {
+    data.settings
+        .update(*guild_id, |settings| change(&mut settings.filters));
+    let filters = data.settings.get(*guild_id).filters;
+
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.effects().filters.set(filters.clone());
+    }
+    filters
+}
 
-                // Parse each line as a JSON object
-                let video_info: Value =
-                    serde_json::from_str(line).expect("Failed to parse JSON line");
+pub async fn clear(guild_id: &GuildId, data: &Data) {
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.clear();
+    }
+}
 
-                // Extract the 'url' field from the JSON object
-                if let Some(url) = video_info.get("url").and_then(|u| u.as_str()) {
-                    result.push(url.to_string());
-                } else {
-                    eprintln!("No URL found in video entry");
-                }
-            }
+pub async fn skip_song(guild_id: &GuildId, data: &Data) {
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.skip();
+    }
+}
 
-            result
-        }
+pub async fn pause_song(guild_id: &GuildId, data: &Data) {
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.pause();
     }
 }
 
-async fn get_multiple_songs(data: &Data, url: String) -> Option<MultipleSongs> {
-    if url.contains("list=") {
-        Some(MultipleSongs::YtPlaylist(
-            get_urls_playlist(url.clone(), PLAYLIST_LIMIT, &data.spotify_client).await,
-        ))
-    } else if let Some(spoty_id) = spotify::get_spoti_playlist_id(&url) {
-        Some(MultipleSongs::SpotiPlaylist(
-            get_urls_playlist(spoty_id.to_string(), PLAYLIST_LIMIT, &data.spotify_client).await,
-        ))
-    } else if let Some(album_id) = spotify::get_spoti_album_id(&url) {
-        Some(MultipleSongs::SpotiAlbum(
-            (spotify::get_urls_album(album_id.to_string(), &data.spotify_client)).await,
-        ))
-    } else {
-        None
+pub async fn resume_song(guild_id: &GuildId, data: &Data) {
+    if let Some(player) = existing_player(guild_id, data).await {
+        player.resume();
     }
 }
 
@@ -604,3 +667,36 @@ pub async fn suggest_queries(
         .await
         .expect("Error finding suggestions")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_indexes_and_ranges() {
+        assert_eq!(parse_range("3"), Some(3..=3));
+        assert_eq!(parse_range("3-7"), Some(3..=7));
+        assert_eq!(parse_range(" 3 - 7 "), Some(3..=7));
+        assert_eq!(parse_range("4-4"), Some(4..=4));
+    }
+
+    #[test]
+    fn lists_the_first_songs_not_found() {
+        let queries = (1..=12).map(|n| format!("song {}", n)).collect::<Vec<_>>();
+        assert_eq!(
+            not_found_message(&queries[..2]),
+            "**Not found:**\n- song 1\n- song 2"
+        );
+
+        let message = not_found_message(&queries);
+        assert!(message.contains("- song 10\n*...and 2 more.*"));
+        assert!(!message.contains("song 11"));
+    }
+
+    #[test]
+    fn rejects_reversed_and_malformed_ranges() {
+        for input in ["7-3", "", "-", "3-", "-7", "a", "3-b", "1-2-3", "-1"] {
+            assert_eq!(parse_range(input), None, "{input:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, UserId};
+
+use crate::{queue::QueuedTrack, settings::JsonWriter};
+
+/// Played tracks kept per guild, the oldest are dropped first.
+const HISTORY_LEN: usize = 200;
+
+/// A track that finished or was skipped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayedTrack {
+    pub title: String,
+    pub url: Option<String>,
+    pub requester: UserId,
+    /// Unix time in seconds.
+    pub played_at: u64,
+}
+
+impl PlayedTrack {
+    /// What to give `/play` to get the track again, tracks without a link are searched by title.
+    pub fn query(&self) -> &str {
+        self.url.as_deref().unwrap_or(&self.title)
+    }
+
+    /// One `/history` line: title, who asked for it and when it played.
+    pub fn describe(&self) -> String {
+        match &self.url {
+            Some(url) => format!(
+                "[{}]({}) <@{}> <t:{}:R>",
+                self.title, url, self.requester, self.played_at
+            ),
+            None => format!(
+                "**{}** <@{}> <t:{}:R>",
+                self.title, self.requester, self.played_at
+            ),
+        }
+    }
+}
+
+/// What each guild played, persisted as JSON so it survives restarts.
+pub struct History {
+    writer: JsonWriter<HashMap<GuildId, VecDeque<PlayedTrack>>>,
+    guilds: Mutex<HashMap<GuildId, VecDeque<PlayedTrack>>>,
+}
+
+impl History {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let guilds = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring malformed history file {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        History {
+            writer: JsonWriter::new(path),
+            guilds: Mutex::new(guilds),
+        }
+    }
+
+    pub fn record(&self, guild_id: GuildId, entry: &QueuedTrack) {
+        let mut guilds = self.guilds.lock().unwrap();
+        let played = guilds.entry(guild_id).or_default();
+        if played.len() == HISTORY_LEN {
+            played.pop_front();
+        }
+        played.push_back(PlayedTrack {
+            title: entry.title.clone(),
+            url: entry.url.clone(),
+            requester: entry.requester,
+            played_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+
+        // Written in the background, the player task records every track that ends.
+        self.writer.save(guilds.clone());
+    }
+
+    /// Played tracks of the guild, the most recent first.
+    pub fn recent(&self, guild_id: GuildId) -> Vec<PlayedTrack> {
+        self.guilds
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .map(|played| played.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The `n`th most recent track, starting from 1.
+    pub fn get(&self, guild_id: GuildId, n: usize) -> Option<PlayedTrack> {
+        let guilds = self.guilds.lock().unwrap();
+        let played = guilds.get(&guild_id)?;
+        played.iter().rev().nth(n.checked_sub(1)?).cloned()
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serenity::all::{Context, GuildId, Timestamp, VoiceState};
+use tokio::time;
+
+use crate::{player::PlayerHandle, settings::SettingsStore, soundboard::Clips};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaveReason {
+    Idle,
+    EmptyChannel,
+    Kicked,
+}
+
+impl fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaveReason::Idle => write!(f, "idle"),
+            LeaveReason::EmptyChannel => write!(f, "empty channel"),
+            LeaveReason::Kicked => write!(f, "kicked"),
+        }
+    }
+}
+
+/// Owns the idle timers of every guild the bot is connected in and makes it leave
+/// when a guild went quiet for too long or nobody is listening anymore.
+#[derive(Clone)]
+pub struct InactivitySupervisor {
+    last_interactions: Arc<Mutex<HashMap<GuildId, Instant>>>,
+    last_leaves: Arc<Mutex<HashMap<GuildId, (LeaveReason, Timestamp)>>>,
+    players: Arc<tokio::sync::Mutex<HashMap<GuildId, PlayerHandle>>>,
+    settings: Arc<SettingsStore>,
+    clips: Clips,
+}
+
+impl InactivitySupervisor {
+    pub fn new(
+        players: Arc<tokio::sync::Mutex<HashMap<GuildId, PlayerHandle>>>,
+        settings: Arc<SettingsStore>,
+        clips: Clips,
+    ) -> Self {
+        InactivitySupervisor {
+            last_interactions: Arc::new(Mutex::new(HashMap::new())),
+            last_leaves: Arc::new(Mutex::new(HashMap::new())),
+            players,
+            settings,
+            clips,
+        }
+    }
+
+    /// Starts the single loop checking idle timers, call it once.
+    pub fn start(&self, ctx: Context) {
+        let supervisor = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                supervisor.check_all(&ctx).await;
+            }
+        });
+    }
+
+    /// Records an interaction in the guild, resetting its idle timer.
+    pub fn touch(&self, guild_id: GuildId) {
+        self.last_interactions
+            .lock()
+            .unwrap()
+            .insert(guild_id, Instant::now());
+    }
+
+    /// Stops watching a guild, to be called before leaving on purpose.
+    pub fn forget(&self, guild_id: GuildId) {
+        self.last_interactions.lock().unwrap().remove(&guild_id);
+    }
+
+    pub fn last_leave(&self, guild_id: GuildId) -> Option<(LeaveReason, Timestamp)> {
+        self.last_leaves.lock().unwrap().get(&guild_id).copied()
+    }
+
+    pub async fn on_voice_state_update(&self, ctx: &Context, new: &VoiceState) {
+        let Some(guild_id) = new.guild_id else {
+            return;
+        };
+        if !self.is_watched(guild_id) {
+            return;
+        }
+
+        if new.user_id == ctx.cache.current_user().id {
+            // Songbird already dropped the call, we only need to clean up after it.
+            if new.channel_id.is_none() {
+                self.record_leave(guild_id, LeaveReason::Kicked).await;
+            }
+        } else if bot_is_alone(ctx, guild_id) == Some(true) {
+            self.leave(ctx, guild_id, LeaveReason::EmptyChannel).await;
+        }
+    }
+
+    async fn check_all(&self, ctx: &Context) {
+        let watched: Vec<(GuildId, Instant)> = self
+            .last_interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(guild_id, instant)| (*guild_id, *instant))
+            .collect();
+
+        for (guild_id, last_interaction) in watched {
+            if bot_is_alone(ctx, guild_id) == Some(true) {
+                self.leave(ctx, guild_id, LeaveReason::EmptyChannel).await;
+                continue;
+            }
+
+            let timeout = Duration::from_secs(self.settings.get(guild_id).idle_timeout_secs);
+            let queued_cnt = match self.players.lock().await.get(&guild_id) {
+                Some(player) => player.snapshot().len(),
+                None => 0,
+            };
+
+            //If no song is playing and the interaction time is over, quit.
+            if last_interaction.elapsed() >= timeout && queued_cnt == 0 {
+                self.leave(ctx, guild_id, LeaveReason::Idle).await;
+            }
+        }
+    }
+
+    /// Leaves because someone asked to, no reason is recorded.
+    pub async fn quit(&self, ctx: &Context, guild_id: GuildId) {
+        self.forget(guild_id);
+        self.stop_playback(guild_id).await;
+        disconnect(ctx, guild_id).await;
+    }
+
+    async fn leave(&self, ctx: &Context, guild_id: GuildId, reason: LeaveReason) {
+        self.record_leave(guild_id, reason).await;
+        disconnect(ctx, guild_id).await;
+    }
+
+    async fn record_leave(&self, guild_id: GuildId, reason: LeaveReason) {
+        self.forget(guild_id);
+        self.last_leaves
+            .lock()
+            .unwrap()
+            .insert(guild_id, (reason, Timestamp::now()));
+        self.stop_playback(guild_id).await;
+
+        println!("Left voice channel in guild {}: {}", guild_id, reason);
+    }
+
+    /// Empties the queue and drops the clips, which never report their end once the
+    /// call is gone, so the music is not left ducked after a rejoin.
+    async fn stop_playback(&self, guild_id: GuildId) {
+        self.clips.clear(guild_id);
+        if let Some(player) = self.players.lock().await.get(&guild_id) {
+            player.unduck();
+            player.clear();
+        }
+    }
+
+    fn is_watched(&self, guild_id: GuildId) -> bool {
+        self.last_interactions
+            .lock()
+            .unwrap()
+            .contains_key(&guild_id)
+    }
+}
+
+async fn disconnect(ctx: &Context, guild_id: GuildId) {
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialisation")
+        .clone();
+    let _ = manager.leave(guild_id).await;
+}
+
+/// Whether only bots are left in the bot's voice channel,
+/// `None` if the bot is not in a voice channel as far as the cache knows.
+fn bot_is_alone(ctx: &Context, guild_id: GuildId) -> Option<bool> {
+    let bot_id = ctx.cache.current_user().id;
+    let guild = guild_id.to_guild_cached(ctx)?;
+    let bot_channel = guild.voice_states.get(&bot_id)?.channel_id?;
+
+    let listeners = guild
+        .voice_states
+        .values()
+        .filter(|state| state.channel_id == Some(bot_channel))
+        .filter(|state| !state.member.as_ref().is_some_and(|member| member.user.bot))
+        .count();
+
+    Some(listeners == 0)
+}
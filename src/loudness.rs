@@ -0,0 +1,593 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    f64::consts::PI,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use songbird::input::codecs::{CODEC_REGISTRY, PROBE};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use crate::{
+    pcm::{Biquad, Processor},
+    settings::save_json,
+};
+
+/// Blocks quieter than this are silence and left out of every measurement.
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks this far below the running estimate are quiet passages, they do not raise the gain.
+const RUNNING_GATE: f64 = 20.0;
+/// Weight of each new 400ms block in the running estimate, about 10 seconds of memory.
+const RUNNING_WEIGHT: f64 = 0.01;
+/// The running estimate is trusted once this many blocks were measured.
+const RUNNING_WARMUP: u32 = 20;
+const MAX_BOOST_DB: f64 = 6.0;
+const MAX_CUT_DB: f64 = 15.0;
+/// How fast the gain of streamed music follows the estimate.
+const GAIN_DB_PER_SEC: f64 = 1.5;
+/// Soundboard files are raised by at most this much, they are often very quiet on purpose.
+const MAX_CLIP_BOOST_DB: f64 = 12.0;
+
+pub const DEFAULT_TARGET_LUFS: f64 = -16.0;
+
+/// Loudness of a mean square, in LUFS.
+fn lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn db_to_gain(db: f64) -> f32 {
+    10f64.powf(db / 20.0) as f32
+}
+
+/// The two K-weighting filters of ITU-R BS.1770 for `sample_rate`.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(sample_rate);
+
+    // High shelf modelling the head.
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // High pass.
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Measures the loudness of interleaved audio in overlapping 400ms blocks, as EBU R128 does.
+struct LoudnessMeter {
+    sample_rate: u32,
+    filters: Vec<[Biquad; 2]>,
+    /// Frames in 100ms, blocks overlap by three of these.
+    step_len: usize,
+    step_frames: usize,
+    step_energy: f64,
+    /// Mean squares of the last four steps.
+    steps: VecDeque<f64>,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        LoudnessMeter {
+            sample_rate,
+            filters: vec![k_weighting(sample_rate); channels],
+            step_len: (sample_rate / 10).max(1) as usize,
+            step_frames: 0,
+            step_energy: 0.0,
+            steps: VecDeque::with_capacity(4),
+        }
+    }
+
+    /// Measures `samples`, calling `on_block` with the mean square of every block completed.
+    fn push(&mut self, samples: &[f32], mut on_block: impl FnMut(f64)) {
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact(channels) {
+            for (sample, [shelf, high_pass]) in frame.iter().zip(&mut self.filters) {
+                let weighted = high_pass.process(shelf.process(f64::from(*sample)));
+                self.step_energy += weighted * weighted;
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == self.step_len {
+                if self.steps.len() == 4 {
+                    self.steps.pop_front();
+                }
+                self.steps
+                    .push_back(self.step_energy / self.step_len as f64);
+                self.step_frames = 0;
+                self.step_energy = 0.0;
+
+                if self.steps.len() == 4 {
+                    on_block(self.steps.iter().sum::<f64>() / 4.0);
+                }
+            }
+        }
+    }
+
+    /// Mean square of everything measured, for recordings shorter than a block.
+    fn short_recording(&self) -> Option<f64> {
+        let frames = self.steps.len() * self.step_len + self.step_frames;
+        let energy = self
+            .steps
+            .iter()
+            .map(|step| step * self.step_len as f64)
+            .sum::<f64>()
+            + self.step_energy;
+        (frames > 0).then(|| energy / frames as f64)
+    }
+}
+
+/// Integrated loudness of a whole recording, from the mean squares of its blocks.
+fn integrated(blocks: &[f64]) -> Option<f64> {
+    let gated = |threshold: f64| {
+        let kept = blocks
+            .iter()
+            .filter(|energy| lufs(**energy) > threshold)
+            .collect::<Vec<_>>();
+        (!kept.is_empty()).then(|| kept.iter().copied().sum::<f64>() / kept.len() as f64)
+    };
+
+    let relative_gate = lufs(gated(ABSOLUTE_GATE)?) - 10.0;
+    gated(relative_gate.max(ABSOLUTE_GATE)).map(lufs)
+}
+
+/// The target loudness of a guild, `None` while normalization is off.
+/// Shared with the tracks playing, so a new target applies to them right away.
+#[derive(Clone, Default)]
+pub struct Normalization {
+    target: Arc<Mutex<Option<f64>>>,
+}
+
+impl Normalization {
+    pub fn new(target: Option<f64>) -> Self {
+        Normalization {
+            target: Arc::new(Mutex::new(target)),
+        }
+    }
+
+    pub fn target(&self) -> Option<f64> {
+        *self.target.lock().unwrap()
+    }
+
+    pub fn set_target(&self, target: Option<f64>) {
+        *self.target.lock().unwrap() = target;
+    }
+}
+
+/// Moves streamed music towards the target loudness, following a running estimate.
+pub struct Normalizer {
+    normalization: Normalization,
+    meter: Option<LoudnessMeter>,
+    /// Mean square of recent blocks.
+    estimate: Option<f64>,
+    blocks: u32,
+    gain_db: f64,
+}
+
+impl Normalizer {
+    pub fn new(normalization: Normalization) -> Self {
+        Normalizer {
+            normalization,
+            meter: None,
+            estimate: None,
+            blocks: 0,
+            gain_db: 0.0,
+        }
+    }
+}
+
+impl Processor for Normalizer {
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, sample_rate: u32) {
+        let target = self.normalization.target();
+        // Nothing to measure or undo while normalization is off.
+        if target.is_none() && self.gain_db == 0.0 {
+            return;
+        }
+
+        if target.is_some() {
+            let meter = self
+                .meter
+                .get_or_insert_with(|| LoudnessMeter::new(sample_rate, channels));
+            let (estimate, blocks) = (&mut self.estimate, &mut self.blocks);
+            meter.push(samples, |energy| {
+                let loudness = lufs(energy);
+                let quiet =
+                    estimate.is_some_and(|estimate| loudness < lufs(estimate) - RUNNING_GATE);
+                if loudness <= ABSOLUTE_GATE || quiet {
+                    return;
+                }
+                *estimate = Some(match *estimate {
+                    Some(estimate) => estimate + (energy - estimate) * RUNNING_WEIGHT,
+                    None => energy,
+                });
+                *blocks += 1;
+            });
+        }
+
+        let wanted_db = match (target, self.estimate) {
+            (Some(target), Some(estimate)) if self.blocks >= RUNNING_WARMUP => {
+                (target - lufs(estimate)).clamp(-MAX_CUT_DB, MAX_BOOST_DB)
+            }
+            (Some(_), _) => self.gain_db,
+            (None, _) => 0.0,
+        };
+
+        // The gain moves slowly, ramping across the samples of this packet.
+        let frames = samples.len() / channels.max(1);
+        let max_change = GAIN_DB_PER_SEC * frames as f64 / f64::from(sample_rate);
+        let from = db_to_gain(self.gain_db);
+        self.gain_db += (wanted_db - self.gain_db).clamp(-max_change, max_change);
+        let to = db_to_gain(self.gain_db);
+        if from == 1.0 && to == 1.0 {
+            return;
+        }
+
+        for (index, frame) in samples.chunks_exact_mut(channels.max(1)).enumerate() {
+            let gain = from + (to - from) * index as f32 / frames as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // The estimate still describes the track, only the filters restart.
+        if let Some(meter) = self.meter.as_mut() {
+            *meter = LoudnessMeter::new(meter.sample_rate, meter.filters.len());
+        }
+    }
+}
+
+/// The measured loudness of a soundboard file, along with what identifies its version.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MeasuredFile {
+    lufs: f64,
+    size: u64,
+    /// Unix time in seconds.
+    modified: u64,
+}
+
+/// Loudness of every soundboard file, measured once and persisted as JSON.
+pub struct ClipLoudness {
+    path: PathBuf,
+    files: Mutex<HashMap<String, MeasuredFile>>,
+}
+
+impl ClipLoudness {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let files = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring malformed loudness file {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        ClipLoudness {
+            path,
+            files: Mutex::new(files),
+        }
+    }
+
+    /// Measures the files under `folder` that are new or changed since they were measured.
+    /// Blocking, meant to run once at startup.
+    pub fn analyse_all(&self, folder: &Path, names: &[String]) {
+        let mut measured = 0;
+        for name in names {
+            let file = folder.join(name);
+            let Some((size, modified)) = version(&file) else {
+                continue;
+            };
+            let known = self
+                .files
+                .lock()
+                .unwrap()
+                .get(name)
+                .is_some_and(|known| known.size == size && known.modified == modified);
+            if known {
+                continue;
+            }
+
+            match measure(&file) {
+                Ok(Some(lufs)) => {
+                    self.files.lock().unwrap().insert(
+                        name.clone(),
+                        MeasuredFile {
+                            lufs,
+                            size,
+                            modified,
+                        },
+                    );
+                    measured += 1;
+                }
+                Ok(None) => println!("{} is silent, it is played as it is", name),
+                Err(e) => eprintln!("Failed to measure the loudness of {}: {}", name, e),
+            }
+        }
+
+        if measured > 0 {
+            println!("Measured the loudness of {} soundboard files", measured);
+            let files = self.files.lock().unwrap();
+            if let Err(e) = save_json(&self.path, &*files) {
+                eprintln!("Failed to save loudness to {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    /// Volume that brings the file to `target` LUFS, 1.0 if it was not measured.
+    pub fn gain(&self, name: &str, target: f64) -> f32 {
+        match self.files.lock().unwrap().get(name) {
+            Some(file) => db_to_gain((target - file.lufs).min(MAX_CLIP_BOOST_DB)),
+            None => 1.0,
+        }
+    }
+}
+
+fn version(file: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(file).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((metadata.len(), modified))
+}
+
+/// Integrated loudness of an audio file in LUFS, `None` if it is silent.
+fn measure(file: &Path) -> Result<Option<f64>, SymphoniaError> {
+    let mut hint = Hint::new();
+    if let Some(extension) = file.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let stream = MediaSourceStream::new(Box::new(fs::File::open(file)?), Default::default());
+    let mut format = PROBE
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format
+        .default_track()
+        .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+    let track_id = track.id;
+    let mut decoder = CODEC_REGISTRY.make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter = None;
+    let mut blocks = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let spec = *decoded.spec();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+
+        meter
+            .get_or_insert_with(|| LoudnessMeter::new(spec.rate, spec.channels.count()))
+            .push(samples.samples(), |energy| blocks.push(energy));
+    }
+
+    if blocks.is_empty() {
+        let energy = meter.and_then(|meter| meter.short_recording());
+        return Ok(energy
+            .map(lufs)
+            .filter(|loudness| *loudness > ABSOLUTE_GATE));
+    }
+    Ok(integrated(&blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// A stereo 997Hz sine, the same on both channels, `peak_db` below full scale.
+    fn sine(peak_db: f64, secs: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(peak_db / 20.0);
+        (0..(secs * f64::from(RATE)) as usize)
+            .flat_map(|n| {
+                let phase = 2.0 * PI * 997.0 * n as f64 / f64::from(RATE);
+                let sample = (amplitude * phase.sin()) as f32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn silence(secs: f64) -> Vec<f32> {
+        vec![0.0; (secs * f64::from(RATE)) as usize * 2]
+    }
+
+    fn blocks(samples: &[f32]) -> Vec<f64> {
+        let mut blocks = Vec::new();
+        LoudnessMeter::new(RATE, 2).push(samples, |energy| blocks.push(energy));
+        blocks
+    }
+
+    /// Writes `samples` as a 16-bit stereo WAV file.
+    fn wav(samples: &[f32]) -> PathBuf {
+        let path = env::temp_dir().join(format!("loudness-{}.wav", uuid::Uuid::new_v4()));
+        let data_len = samples.len() as u32 * 2;
+        let mut bytes = Vec::new();
+        bytes.extend(b"RIFF");
+        bytes.extend((36 + data_len).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(RATE.to_le_bytes());
+        bytes.extend((RATE * 4).to_le_bytes());
+        bytes.extend(4u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend(data_len.to_le_bytes());
+        for sample in samples {
+            bytes.extend(((sample * 32767.0).round() as i16).to_le_bytes());
+        }
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} LUFS instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn sine_tones_match_the_reference_loudness() {
+        // EBU Tech 3341: a -23dBFS 1kHz sine on both channels is -23 LUFS.
+        for peak_db in [-23.0, -33.0, -10.0] {
+            let loudness = integrated(&blocks(&sine(peak_db, 10.0))).unwrap();
+            assert_close(loudness, peak_db, 0.1);
+        }
+    }
+
+    #[test]
+    fn files_are_measured() {
+        let path = wav(&sine(-23.0, 5.0));
+        let loudness = measure(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_close(loudness, -23.0, 0.1);
+    }
+
+    #[test]
+    fn short_files_are_measured_whole() {
+        let path = wav(&sine(-23.0, 0.2));
+        let loudness = measure(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_close(loudness, -23.0, 0.2);
+    }
+
+    #[test]
+    fn silence_does_not_pull_the_loudness_down() {
+        let mut samples = sine(-23.0, 5.0);
+        samples.extend(silence(20.0));
+        // Only the blocks straddling the end of the tone are partly silent.
+        assert_close(integrated(&blocks(&samples)).unwrap(), -23.0, 0.2);
+
+        assert_eq!(integrated(&blocks(&silence(5.0))), None);
+        let path = wav(&silence(1.0));
+        let loudness = measure(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loudness, None);
+    }
+
+    #[test]
+    fn quiet_passages_are_gated_relative_to_the_rest() {
+        // -45 LUFS is above the absolute gate but 22dB under the rest.
+        let mut samples = sine(-23.0, 10.0);
+        samples.extend(sine(-45.0, 10.0));
+        assert_close(integrated(&blocks(&samples)).unwrap(), -23.0, 0.2);
+
+        // Within 10dB it counts, pulling the value down.
+        let mut samples = sine(-23.0, 10.0);
+        samples.extend(sine(-29.0, 10.0));
+        let loudness = integrated(&blocks(&samples)).unwrap();
+        assert!(loudness < -24.0, "{loudness} LUFS");
+    }
+
+    /// The gain the normalizer reached after `secs` of `peak_db` sine.
+    fn settled_gain(peak_db: f64, secs: usize) -> f64 {
+        let mut normalizer = Normalizer::new(Normalization::new(Some(DEFAULT_TARGET_LUFS)));
+        for _ in 0..secs {
+            normalizer.process(&mut sine(peak_db, 1.0), 2, RATE);
+        }
+        normalizer.gain_db
+    }
+
+    #[test]
+    fn normalizer_gain_is_clamped() {
+        assert_eq!(settled_gain(-60.0, 30), MAX_BOOST_DB);
+        assert_eq!(settled_gain(0.0, 30), -MAX_CUT_DB);
+        assert_close(settled_gain(-20.0, 30), 4.0, 0.3);
+    }
+
+    #[test]
+    fn normalizer_gain_moves_slowly() {
+        // The estimate is trusted after two seconds, then the gain moves 1.5dB a second.
+        let gain = settled_gain(-60.0, 4);
+        assert!(
+            gain > 0.0 && gain <= 2.0 * GAIN_DB_PER_SEC + 0.01,
+            "{gain}dB"
+        );
+    }
+
+    fn tone(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let sample = (n as f32 * 0.05).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_is_metered_while_off() {
+        let mut normalizer = Normalizer::new(Normalization::new(None));
+        let mut samples = tone(48_000);
+        normalizer.process(&mut samples, 2, 48_000);
+
+        assert_eq!(samples, tone(48_000));
+        assert!(normalizer.meter.is_none());
+        assert!(normalizer.estimate.is_none());
+    }
+
+    #[test]
+    fn turning_it_off_stops_metering() {
+        let normalization = Normalization::new(Some(DEFAULT_TARGET_LUFS));
+        let mut normalizer = Normalizer::new(normalization.clone());
+        for _ in 0..10 {
+            normalizer.process(&mut tone(48_000), 2, 48_000);
+        }
+        assert!(normalizer.blocks > 0);
+
+        normalization.set_target(None);
+        let blocks = normalizer.blocks;
+        normalizer.process(&mut tone(48_000), 2, 48_000);
+        assert_eq!(normalizer.blocks, blocks);
+    }
+}
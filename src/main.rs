@@ -1,36 +1,79 @@
+mod filters;
 mod general;
+mod history;
+mod inactivity;
+mod loudness;
+mod match_cache;
+mod panel;
+mod pcm;
+mod player;
+mod playlists;
+mod progress;
+mod queue;
+mod queue_file;
+mod resolver;
+mod seek;
+mod settings;
 mod soundboard;
 mod spotify;
 
 use dotenvy::dotenv;
+use filters::{FilterPreset, EQ_BANDS, MAX_EQ_GAIN_DB, MAX_PITCH, MAX_SPEED, MIN_SPEED};
 use general::suggest_queries;
+use history::History;
+use inactivity::InactivitySupervisor;
+use loudness::ClipLoudness;
+use match_cache::MatchCache;
+use player::{DuckMode, LoopMode, Placement, PlayerHandle, QueueEdited};
+use playlists::{PlaylistError, PlaylistScope, PlaylistStore, SavedTrack};
 use poise::serenity_prelude as serenity;
-use poise::serenity_prelude::prelude::TypeMapKey;
+use queue::{QueueEdit, MAX_CROSSFADE_SECS};
+use queue_file::ExportFormat;
 use reqwest::Client as HttpClient;
-use rspotify::{ClientCredsSpotify, Credentials};
+use resolver::{
+    HttpResolver, LocalFileResolver, Resolvers, SpotifyResolver, YoutubeBackend,
+    YoutubePlaylistResolver, YoutubeResolver, YtDlp,
+};
+use rspotify::Credentials;
 use serenity::all::GuildId;
-use songbird::{tracks::TrackQueue, SerenityInit};
-use std::{collections::HashMap, env, fs::File, io::Cursor, sync::Arc, time::Instant};
+use settings::SettingsStore;
+use songbird::SerenityInit;
+use soundboard::Clips;
+use spotify::SpotifyClient;
+use std::{collections::HashMap, env, fs::File, io::Cursor, path::Path, sync::Arc};
 use tokio::sync::Mutex;
-use tokio_util::sync::CancellationToken;
 
 const AUDIO_PATH: &str = "./audio/";
+const MUSIC_PATH: &str = "./music/";
+const SETTINGS_PATH: &str = "./data/settings.json";
+const DEFAULT_LOOKAHEAD_TRACKS: usize = 2;
+const DEFAULT_PLAYLIST_CONCURRENCY: usize = 4;
+const MATCH_CACHE_PATH: &str = "./data/spotify_matches.json";
+const HISTORY_PATH: &str = "./data/history.json";
+const PLAYLISTS_PATH: &str = "./data/playlists.json";
+const CLIP_LOUDNESS_PATH: &str = "./data/soundboard_loudness.json";
+/// Larger queue files are not downloaded.
+const MAX_IMPORT_BYTES: u32 = 1024 * 1024;
 
 struct Data {
-    last_interaction: Arc<Mutex<Instant>>,
+    inactivity: InactivitySupervisor,
+    settings: Arc<SettingsStore>,
     soundboard_data: Vec<(String, String, String)>,
-    tracks: Arc<Mutex<HashMap<GuildId, (TrackQueue, Vec<String>)>>>,
-    spotify_client: ClientCredsSpotify,
-    playlist_cancellation: Mutex<HashMap<GuildId, CancellationToken>>,
+    /// Soundboard clips playing now.
+    clips: Clips,
+    clip_loudness: Arc<ClipLoudness>,
+    players: Arc<Mutex<HashMap<GuildId, PlayerHandle>>>,
+    resolvers: Resolvers,
+    /// How many queued tracks are loaded ahead of the one playing.
+    lookahead: usize,
+    /// How many playlist tracks are looked up at the same time.
+    playlist_concurrency: usize,
+    match_cache: Arc<MatchCache>,
+    history: Arc<History>,
+    playlists: PlaylistStore,
     reqwest_client: reqwest::Client,
 }
 
-struct HttpKey;
-
-impl TypeMapKey for HttpKey {
-    type Value = HttpClient;
-}
-
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
@@ -38,11 +81,11 @@ type Context<'a> = poise::Context<'a, Data, Error>;
 #[poise::command(slash_command, prefix_command)]
 async fn soundboard(ctx: Context<'_>) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    general::delete_messages(&ctx.serenity_context(), &ctx.channel_id()).await;
+    general::delete_messages(ctx.serenity_context(), &ctx.channel_id()).await;
     ctx.say("Done").await?;
 
     let voice_channel_id = match general::get_user_voice_channel(
-        &ctx.serenity_context(),
+        ctx.serenity_context(),
         &ctx.author().id,
         &ctx.guild_id().unwrap(),
     )
@@ -56,7 +99,7 @@ async fn soundboard(ctx: Context<'_>) -> Result<(), Error> {
     };
 
     soundboard::soundboard_handler(
-        &ctx.serenity_context(),
+        ctx.serenity_context(),
         &ctx.channel_id(),
         ctx.guild_id().unwrap(),
         &voice_channel_id,
@@ -78,7 +121,7 @@ async fn skip(ctx: Context<'_>) -> Result<(), Error> {
 /// Skips the current track and clears the queue
 #[poise::command(slash_command, prefix_command)]
 async fn clear(ctx: Context<'_>) -> Result<(), Error> {
-    // Since adding the songs takes a lot of time, if clear is called while songs are added, only the already loaded tracks are cleared, the other async function will keep adding.
+    // Also cancels a playlist that is still being added, tracks resolved after this point are dropped.
     general::clear(&ctx.guild_id().unwrap(), ctx.data()).await;
     ctx.say("Cleared all queued songs.").await?;
     Ok(())
@@ -108,12 +151,100 @@ async fn play(
     #[description = "Url or title"]
     #[autocomplete = "suggest_queries"]
     title: String,
+    #[description = "For playlists and albums: first track to enqueue."]
+    #[min = 1]
+    start: Option<usize>,
+    #[description = "For playlists and albums: last track to enqueue."]
+    #[min = 1]
+    end: Option<usize>,
+    #[description = "For artist links: enqueue every album and single instead of the top tracks."]
+    discography: Option<bool>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+
+    if start == Some(0) || end == Some(0) {
+        ctx.say("Tracks are numbered from 1.").await?;
+        return Ok(());
+    }
+    if let (Some(start), Some(end)) = (start, end) {
+        if end < start {
+            ctx.say(format!(
+                "`end` ({}) cannot be before `start` ({}).",
+                end, start
+            ))
+            .await?;
+            return Ok(());
+        }
+    }
+
+    let max_tracks = ctx
+        .data()
+        .settings
+        .get(ctx.guild_id().unwrap())
+        .playlist_limit;
+    let mut range = general::PlaylistRange::new(start, end, max_tracks);
+    range.discography = discography.unwrap_or(false);
+
+    play_placed(ctx, title, range, Placement::Last).await
+}
+
+/// Plays a song or playlist right after the current track.
+#[poise::command(slash_command, prefix_command)]
+async fn playnext(
+    ctx: Context<'_>,
+    #[description = "Url or title"]
+    #[autocomplete = "suggest_queries"]
+    title: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+
+    let max_tracks = ctx
+        .data()
+        .settings
+        .get(ctx.guild_id().unwrap())
+        .playlist_limit;
+    let range = general::PlaylistRange::new(None, None, max_tracks);
+    play_placed(ctx, title, range, Placement::Next).await
+}
+
+/// Plays a song or playlist right away, interrupting the current track.
+#[poise::command(slash_command, prefix_command)]
+async fn playnow(
+    ctx: Context<'_>,
+    #[description = "Url or title"]
+    #[autocomplete = "suggest_queries"]
+    title: String,
+    #[description = "Play the interrupted track again afterwards, from where it was (default true)."]
+    resume: Option<bool>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
 
+    let max_tracks = ctx
+        .data()
+        .settings
+        .get(ctx.guild_id().unwrap())
+        .playlist_limit;
+    let range = general::PlaylistRange::new(None, None, max_tracks);
+    let placement = Placement::Now {
+        resume: resume.unwrap_or(true),
+    };
+    play_placed(ctx, title, range, placement).await
+}
+
+async fn play_placed(
+    ctx: Context<'_>,
+    query: String,
+    range: general::PlaylistRange,
+    placement: Placement,
+) -> Result<(), Error> {
+    let request = general::SongRequest {
+        query,
+        range,
+        placement,
+    };
     general::play_songs(
-        &ctx.serenity_context(),
-        title,
+        ctx.serenity_context(),
+        request,
         ctx.guild_id().unwrap(),
         ctx.channel_id(),
         &ctx.author().id,
@@ -131,10 +262,8 @@ async fn list(ctx: Context<'_>) -> Result<(), Error> {
     const MAX_MSG_LEN: usize = 2000;
     ctx.defer().await?;
 
-    let hmap = ctx.data().tracks.lock().await;
-
-    let titles = match &hmap.get(&ctx.guild_id().unwrap()) {
-        Some(tuple) => tuple.1.clone(),
+    let snapshot = match general::existing_player(&ctx.guild_id().unwrap(), ctx.data()).await {
+        Some(player) => player.snapshot(),
         None => {
             let _ = ctx.say("No songs queued.").await?;
             return Ok(());
@@ -143,13 +272,17 @@ async fn list(ctx: Context<'_>) -> Result<(), Error> {
 
     let mut messages = Vec::<String>::new();
 
-    let mut formatted_msg = String::from("");
+    let mut formatted_msg = format!(
+        "*Loop:* **{}** *Shuffle:* **{}**\n",
+        snapshot.loop_mode,
+        if snapshot.shuffle { "On" } else { "Off" }
+    );
 
-    for (index, title) in titles.iter().enumerate() {
+    for (index, entry) in snapshot.entries.iter().enumerate() {
         let tmp_msg = if index == 0 {
-            format!("`{:>2}.`*__ Now playing__:* **{}**\n", index, title)
+            format!("`{:>2}.`*__ Now playing__:* {}\n", index, entry.describe())
         } else {
-            format!("`{:>2}.`**{}**\n", index, title)
+            format!("`{:>2}.`{}\n", index, entry.describe())
         };
 
         if formatted_msg.len() + tmp_msg.len() > MAX_MSG_LEN {
@@ -159,71 +292,868 @@ async fn list(ctx: Context<'_>) -> Result<(), Error> {
         formatted_msg.push_str(&tmp_msg);
     }
 
-    if !formatted_msg.is_empty() {
-        messages.push(formatted_msg.clone());
-    } else {
-        messages.push(String::from("*No songs in queue.*"));
+    if snapshot.entries.is_empty() {
+        formatted_msg.push_str("*No songs in queue.*");
     }
+    messages.push(formatted_msg);
 
+    // Requesters are shown as mentions, without pinging them.
     for message in messages {
-        let _ = ctx.say(message).await?;
+        let reply = poise::CreateReply::default()
+            .content(message)
+            .allowed_mentions(serenity::CreateAllowedMentions::new());
+        let _ = ctx.send(reply).await?;
     }
 
     Ok(())
 }
 
-/// Removes the track at the specified index from the queue.
+/// Removes tracks from the queue, by index, range or requester.
 #[poise::command(slash_command, prefix_command)]
 async fn remove(
     ctx: Context<'_>,
-    #[description = "Index of the track to remove from the queue."] index: usize,
+    #[description = "Index or range of the tracks to remove, like 3 or 3-10."] tracks: Option<
+        String,
+    >,
+    #[description = "Remove every track added by this user."] user: Option<serenity::User>,
 ) -> Result<(), Error> {
-    let mut hmap = ctx.data().tracks.lock().await;
-
-    let (queue, titles) = match hmap.get_mut(&ctx.guild_id().unwrap()) {
-        Some(tuple) => tuple,
-        None => {
-            let _ = ctx.say("No songs queued.").await?;
+    let edit = match (tracks, user) {
+        (Some(tracks), _) => match general::parse_range(&tracks) {
+            Some(range) => QueueEdit::Remove(range),
+            None => {
+                ctx.say("Give an index like `3` or a range like `3-10`.")
+                    .await?;
+                return Ok(());
+            }
+        },
+        (None, Some(user)) => QueueEdit::RemoveBy(user.id),
+        (None, None) => {
+            ctx.say("Give the tracks or the user to remove.").await?;
             return Ok(());
         }
     };
 
-    if index < titles.len() {
-        if index == 0 {
-            let _ = queue.skip();
-        } else {
-            let _ = queue.dequeue(index - 1);
-            titles.remove(index);
+    edit_queue(ctx, edit, |edited| match &edited.removed[..] {
+        [] => String::from("*Nothing to remove.*"),
+        [entry] => format!("*Succesfully removed:* **{}**", entry.title),
+        removed => format!("*Removed* **{} tracks**.", removed.len()),
+    })
+    .await
+}
+
+/// Moves a track to another place in the queue.
+#[poise::command(slash_command, prefix_command, rename = "move")]
+async fn move_track(
+    ctx: Context<'_>,
+    #[description = "Index of the track to move."] from: usize,
+    #[description = "Index it should have afterwards."] to: usize,
+) -> Result<(), Error> {
+    edit_queue(ctx, QueueEdit::Move { from, to }, |_| {
+        format!("*Moved track {} to {}.*", from, to)
+    })
+    .await
+}
+
+/// Swaps two tracks of the queue.
+#[poise::command(slash_command, prefix_command)]
+async fn swap(
+    ctx: Context<'_>,
+    #[description = "Index of the first track."] a: usize,
+    #[description = "Index of the second track."] b: usize,
+) -> Result<(), Error> {
+    edit_queue(ctx, QueueEdit::Swap(a, b), |_| {
+        format!("*Swapped tracks {} and {}.*", a, b)
+    })
+    .await
+}
+
+/// Shuffles the queue, the current track keeps playing.
+#[poise::command(slash_command, prefix_command)]
+async fn shuffle(ctx: Context<'_>) -> Result<(), Error> {
+    edit_queue(ctx, QueueEdit::Shuffle, |_| {
+        String::from("*Shuffled the queue.*")
+    })
+    .await
+}
+
+/// Skips every track before the given one.
+#[poise::command(slash_command, prefix_command)]
+async fn skipto(
+    ctx: Context<'_>,
+    #[description = "Index of the track to play."] index: usize,
+) -> Result<(), Error> {
+    edit_queue(ctx, QueueEdit::SkipTo(index), |edited| {
+        match edited.entries.first() {
+            Some(entry) => format!("*Skipped to:* **{}**", entry.title),
+            None => String::from("*Skipped.*"),
         }
+    })
+    .await
+}
 
-        ctx.say(format!("*Succesfully removed:* **{}**", titles[index]))
-            .await?;
-    } else {
-        ctx.say("Index out of range.").await?;
-    }
+/// Applies `edit` to the queue, replying with what `done` says and the new order.
+async fn edit_queue(
+    ctx: Context<'_>,
+    edit: QueueEdit,
+    done: impl FnOnce(&QueueEdited) -> String,
+) -> Result<(), Error> {
+    let Some(player) = general::existing_player(&ctx.guild_id().unwrap(), ctx.data()).await else {
+        ctx.say("No songs queued.").await?;
+        return Ok(());
+    };
+
+    let content = match player.edit(edit).await {
+        Ok(edited) => format!(
+            "{}\n{}",
+            done(&edited),
+            general::queue_summary(&edited.entries)
+        ),
+        Err(e) => e.to_string(),
+    };
 
+    // Requesters are shown as mentions, without pinging them.
+    let reply = poise::CreateReply::default()
+        .content(content)
+        .allowed_mentions(serenity::CreateAllowedMentions::new());
+    ctx.send(reply).await?;
     Ok(())
 }
 
-/// Seeks FORWARD in the currently playing track by the specified number of seconds.
+/// Moves to a position in the currently playing track, forward or backward.
 #[poise::command(slash_command, prefix_command)]
 async fn seek(
     ctx: Context<'_>,
-    #[description = "Absolute position in seconds to seek to."] seconds: String,
+    #[description = "A time (1:23, 1h2m, 90), an offset (+30, -15) or a percentage (50%)."]
+    position: String,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     general::seek(
-        &ctx.serenity_context(),
+        ctx.serenity_context(),
         &ctx.guild_id().unwrap(),
         &ctx.channel_id(),
         ctx.data(),
-        seconds,
+        position,
+    )
+    .await;
+    ctx.say("Done").await?;
+    Ok(())
+}
+
+/// Shows or sets how many minutes of inactivity make the bot leave the voice channel.
+#[poise::command(slash_command, prefix_command)]
+async fn idle(
+    ctx: Context<'_>,
+    #[description = "Minutes without interactions before leaving."]
+    #[min = 1]
+    minutes: Option<u64>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    if let Some(minutes) = minutes {
+        ctx.data().settings.update(guild_id, |settings| {
+            settings.idle_timeout_secs = minutes * 60
+        });
+    }
+
+    let timeout_minutes = ctx.data().settings.get(guild_id).idle_timeout_secs / 60;
+    let mut message = format!("*Idle timeout:* **{} minutes**.", timeout_minutes);
+
+    if let Some((reason, at)) = ctx.data().inactivity.last_leave(guild_id) {
+        message.push_str(&format!(
+            "\n*Last auto-leave:* **{}** <t:{}:R>.",
+            reason,
+            at.unix_timestamp()
+        ));
+    }
+
+    ctx.say(message).await?;
+    Ok(())
+}
+
+/// Shows or sets the most tracks enqueued from a single playlist or album.
+#[poise::command(slash_command, prefix_command)]
+async fn playlistlimit(
+    ctx: Context<'_>,
+    #[description = "Maximum number of tracks per playlist or album."]
+    #[min = 1]
+    count: Option<usize>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    if let Some(count) = count {
+        ctx.data()
+            .settings
+            .update(guild_id, |settings| settings.playlist_limit = count);
+    }
+
+    let limit = ctx.data().settings.get(guild_id).playlist_limit;
+    ctx.say(format!("*Playlist limit:* **{} tracks**.", limit))
+        .await?;
+    Ok(())
+}
+
+/// Shows or sets the volume of the music, or of the soundboard.
+#[poise::command(slash_command, prefix_command)]
+async fn volume(
+    ctx: Context<'_>,
+    #[description = "Percent of the original level, 100 by default."]
+    #[min = 0]
+    #[max = 200]
+    level: Option<u16>,
+    #[description = "The soundboard volume instead of the music one."] soundboard: Option<bool>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+    let soundboard = soundboard.unwrap_or(false);
+
+    if let Some(level) = level {
+        general::set_volume(&guild_id, ctx.data(), level.min(200), soundboard).await;
+    }
+
+    let settings = ctx.data().settings.get(guild_id);
+    let (name, level) = if soundboard {
+        ("Soundboard volume", settings.soundboard_volume)
+    } else {
+        ("Music volume", settings.music_volume)
+    };
+    ctx.say(format!("*{}:* **{}%**.", name, level)).await?;
+    Ok(())
+}
+
+/// Shows or sets what happens to the music while a soundboard clip plays.
+#[poise::command(slash_command, prefix_command)]
+async fn ducking(
+    ctx: Context<'_>,
+    #[description = "off, duck (lower the music) or pause."] mode: Option<DuckMode>,
+    #[description = "Percent of the music volume kept while ducking."]
+    #[min = 0]
+    #[max = 100]
+    level: Option<u16>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    if mode.is_some() || level.is_some() {
+        ctx.data().settings.update(guild_id, |settings| {
+            if let Some(mode) = mode {
+                settings.duck_mode = mode;
+            }
+            if let Some(level) = level {
+                settings.duck_level = level.min(100);
+            }
+        });
+    }
+
+    let settings = ctx.data().settings.get(guild_id);
+    ctx.say(format!(
+        "*Ducking:* **{}**, *music kept at* **{}%**.",
+        settings.duck_mode, settings.duck_level
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Shows or sets how long the end of a track overlaps the start of the next one.
+#[poise::command(slash_command, prefix_command)]
+async fn crossfade(
+    ctx: Context<'_>,
+    #[description = "Seconds, 0 turns crossfading off."]
+    #[min = 0]
+    #[max = 12]
+    seconds: Option<u64>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    if let Some(seconds) = seconds {
+        general::set_crossfade(&guild_id, ctx.data(), seconds.min(MAX_CROSSFADE_SECS)).await;
+    }
+
+    let secs = ctx.data().settings.get(guild_id).crossfade_secs;
+    if secs == 0 {
+        ctx.say("*Crossfade:* **Off**.").await?;
+    } else {
+        ctx.say(format!("*Crossfade:* **{}s**.", secs)).await?;
+    }
+    Ok(())
+}
+
+/// Shows or sets whether music and soundboard clips are brought to the same loudness.
+#[poise::command(slash_command, prefix_command)]
+async fn normalize(
+    ctx: Context<'_>,
+    #[description = "Turn loudness normalization on or off."] enabled: Option<bool>,
+    #[description = "Target loudness in LUFS, -16 by default."]
+    #[min = -40]
+    #[max = -5]
+    target: Option<f64>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    if enabled.is_some() || target.is_some() {
+        ctx.data().settings.update(guild_id, |settings| {
+            if let Some(enabled) = enabled {
+                settings.normalization = enabled;
+            }
+            if let Some(target) = target {
+                settings.target_lufs = target.clamp(-40.0, -5.0);
+            }
+        });
+        if let Some(player) = general::existing_player(&guild_id, ctx.data()).await {
+            let target = ctx.data().settings.get(guild_id).normalization_target();
+            player.effects().normalization.set_target(target);
+        }
+    }
+
+    let settings = ctx.data().settings.get(guild_id);
+    let state = if settings.normalization { "On" } else { "Off" };
+    ctx.say(format!(
+        "*Normalization:* **{}**, *target* **{} LUFS**.",
+        state, settings.target_lufs
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Audio filters of the server, kept until cleared.
+#[poise::command(
+    slash_command,
+    prefix_command,
+    subcommands(
+        "filter_preset",
+        "filter_speed",
+        "filter_pitch",
+        "filter_eq",
+        "filter_clear",
+        "filter_show"
+    ),
+    subcommand_required
+)]
+async fn filter(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Applies a preset, replacing the previous one.
+#[poise::command(slash_command, prefix_command, rename = "preset")]
+async fn filter_preset(
+    ctx: Context<'_>,
+    #[description = "bassboost, nightcore, vaporwave, 8d or karaoke."] preset: FilterPreset,
+) -> Result<(), Error> {
+    let filters = general::change_filters(&ctx.guild_id().unwrap(), ctx.data(), |filters| {
+        filters.preset = Some(preset)
+    })
+    .await;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Changes the tempo, the pitch stays the same.
+#[poise::command(slash_command, prefix_command, rename = "speed")]
+async fn filter_speed(
+    ctx: Context<'_>,
+    #[description = "1 is the original speed."]
+    #[min = 0.5]
+    #[max = 2.0]
+    speed: f64,
+) -> Result<(), Error> {
+    let filters = general::change_filters(&ctx.guild_id().unwrap(), ctx.data(), |filters| {
+        filters.speed = speed.clamp(MIN_SPEED, MAX_SPEED)
+    })
+    .await;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Changes the pitch, the tempo stays the same.
+#[poise::command(slash_command, prefix_command, rename = "pitch")]
+async fn filter_pitch(
+    ctx: Context<'_>,
+    #[description = "Semitones up, or down if negative."]
+    #[min = -12]
+    #[max = 12]
+    semitones: f64,
+) -> Result<(), Error> {
+    let filters = general::change_filters(&ctx.guild_id().unwrap(), ctx.data(), |filters| {
+        filters.pitch = semitones.clamp(-MAX_PITCH, MAX_PITCH)
+    })
+    .await;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Sets the gain of one band of the equalizer.
+#[poise::command(slash_command, prefix_command, rename = "eq")]
+async fn filter_eq(
+    ctx: Context<'_>,
+    #[description = "1 (32Hz), 2 (64Hz), 3 (125Hz), 4 (250Hz), 5 (500Hz), 6 (1kHz), 7 (2kHz), 8 (4kHz), 9 (8kHz) or 10 (16kHz)."]
+    #[min = 1]
+    #[max = 10]
+    band: usize,
+    #[description = "In dB, from -12 to 12. 0 leaves the band as it is."]
+    #[min = -12]
+    #[max = 12]
+    gain: f64,
+) -> Result<(), Error> {
+    if !(1..=EQ_BANDS.len()).contains(&band) {
+        ctx.say(format!("Bands go from 1 to {}.", EQ_BANDS.len()))
+            .await?;
+        return Ok(());
+    }
+
+    let filters = general::change_filters(&ctx.guild_id().unwrap(), ctx.data(), |filters| {
+        filters.equalizer[band - 1] = gain.clamp(-MAX_EQ_GAIN_DB, MAX_EQ_GAIN_DB)
+    })
+    .await;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Removes every filter.
+#[poise::command(slash_command, prefix_command, rename = "clear")]
+async fn filter_clear(ctx: Context<'_>) -> Result<(), Error> {
+    let filters = general::change_filters(&ctx.guild_id().unwrap(), ctx.data(), |filters| {
+        *filters = Default::default()
+    })
+    .await;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Shows the filters of the server.
+#[poise::command(slash_command, prefix_command, rename = "show")]
+async fn filter_show(ctx: Context<'_>) -> Result<(), Error> {
+    let filters = ctx.data().settings.get(ctx.guild_id().unwrap()).filters;
+    ctx.say(filters.describe()).await?;
+    Ok(())
+}
+
+/// Lists the tracks played in this server, the most recent first.
+#[poise::command(slash_command, prefix_command)]
+async fn history(ctx: Context<'_>) -> Result<(), Error> {
+    const PAGE_LEN: usize = 10;
+
+    let played = ctx.data().history.recent(ctx.guild_id().unwrap());
+    if played.is_empty() {
+        ctx.say("Nothing played yet.").await?;
+        return Ok(());
+    }
+
+    let pages: Vec<String> = played
+        .chunks(PAGE_LEN)
+        .enumerate()
+        .map(|(page, tracks)| {
+            tracks
+                .iter()
+                .enumerate()
+                .map(|(index, track)| {
+                    format!(
+                        "`{:>3}.` {}\n",
+                        page * PAGE_LEN + index + 1,
+                        track.describe()
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    let pages: Vec<&str> = pages.iter().map(String::as_str).collect();
+
+    poise::builtins::paginate(ctx, &pages).await?;
+    Ok(())
+}
+
+/// Plays the last played track again, the current track follows it.
+#[poise::command(slash_command, prefix_command)]
+async fn previous(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let guild_id = ctx.guild_id().unwrap();
+
+    if let Some(player) = general::existing_player(&guild_id, ctx.data()).await {
+        if player.previous().await {
+            ctx.say("Done").await?;
+            return Ok(());
+        }
+    }
+
+    // Nothing played since the bot started, the history still knows.
+    let Some(track) = ctx.data().history.get(guild_id, 1) else {
+        ctx.say("Nothing played yet.").await?;
+        return Ok(());
+    };
+    let max_tracks = ctx.data().settings.get(guild_id).playlist_limit;
+    let range = general::PlaylistRange::new(None, None, max_tracks);
+    play_placed(
+        ctx,
+        track.query().to_string(),
+        range,
+        Placement::Now { resume: true },
+    )
+    .await
+}
+
+/// Queues a track of `/history` again.
+#[poise::command(slash_command, prefix_command)]
+async fn replay(
+    ctx: Context<'_>,
+    #[description = "Number of the track in /history, 1 is the last one played."]
+    #[min = 1]
+    n: usize,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let guild_id = ctx.guild_id().unwrap();
+
+    let Some(track) = ctx.data().history.get(guild_id, n) else {
+        ctx.say(format!("There is no track {} in the history.", n))
+            .await?;
+        return Ok(());
+    };
+    let max_tracks = ctx.data().settings.get(guild_id).playlist_limit;
+    let range = general::PlaylistRange::new(None, None, max_tracks);
+    play_placed(ctx, track.query().to_string(), range, Placement::Last).await
+}
+
+/// Saved playlists of the server and your personal ones.
+#[poise::command(
+    slash_command,
+    prefix_command,
+    subcommands(
+        "playlist_save",
+        "playlist_load",
+        "playlist_list",
+        "playlist_delete",
+        "playlist_add"
+    ),
+    subcommand_required
+)]
+async fn playlist(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Saves the current queue as a playlist, replacing its tracks if it exists.
+#[poise::command(slash_command, prefix_command, rename = "save")]
+async fn playlist_save(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist."] name: String,
+    #[description = "Keep it for yourself instead of the whole server."] personal: Option<bool>,
+) -> Result<(), Error> {
+    let entries = match general::existing_player(&ctx.guild_id().unwrap(), ctx.data()).await {
+        Some(player) => player.snapshot().entries,
+        None => Vec::new(),
+    };
+    if entries.is_empty() {
+        ctx.say("No songs queued.").await?;
+        return Ok(());
+    }
+
+    let tracks = entries
+        .into_iter()
+        .map(|entry| SavedTrack {
+            query: entry.url.clone().unwrap_or_else(|| entry.title.clone()),
+            title: entry.title,
+        })
+        .collect::<Vec<_>>();
+    let count = tracks.len();
+
+    let saved = ctx.data().playlists.save(
+        playlist_scope(ctx, personal),
+        &name,
+        ctx.author().id,
+        is_admin(ctx).await,
+        tracks,
+    );
+    match saved {
+        Ok(()) => ctx.say(format!("*Saved* **{}** *with {} tracks.*", name, count)),
+        Err(e) => ctx.say(e.to_string()),
+    }
+    .await?;
+    Ok(())
+}
+
+/// Enqueues a saved playlist.
+#[poise::command(slash_command, prefix_command, rename = "load")]
+async fn playlist_load(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist."] name: String,
+    #[description = "One of your personal playlists."] personal: Option<bool>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+
+    let Some(playlist) = ctx
+        .data()
+        .playlists
+        .get(playlist_scope(ctx, personal), &name)
+    else {
+        ctx.say(PlaylistError::NotFound(name).to_string()).await?;
+        return Ok(());
+    };
+
+    let queries = playlist
+        .tracks
+        .into_iter()
+        .map(|track| track.query)
+        .collect();
+    let limit = ctx
+        .data()
+        .settings
+        .get(ctx.guild_id().unwrap())
+        .playlist_limit;
+    general::play_queries(
+        ctx.serenity_context(),
+        queries,
+        limit,
+        ctx.guild_id().unwrap(),
+        ctx.channel_id(),
+        &ctx.author().id,
+        ctx.data(),
     )
     .await;
+
     ctx.say("Done").await?;
     Ok(())
 }
 
+/// Lists the playlists of the server and your personal ones.
+#[poise::command(slash_command, prefix_command, rename = "list")]
+async fn playlist_list(ctx: Context<'_>) -> Result<(), Error> {
+    let playlists = ctx
+        .data()
+        .playlists
+        .list(ctx.guild_id().unwrap(), ctx.author().id);
+    if playlists.is_empty() {
+        ctx.say("No saved playlists.").await?;
+        return Ok(());
+    }
+
+    let mut message = String::new();
+    for playlist in playlists {
+        let scope = match playlist.scope {
+            PlaylistScope::Guild(_) => "",
+            PlaylistScope::User(_) => " (personal)",
+        };
+        message.push_str(&format!(
+            "**{}**{} `{} tracks` <@{}>\n",
+            playlist.name,
+            scope,
+            playlist.tracks.len(),
+            playlist.owner
+        ));
+    }
+
+    // Owners are shown as mentions, without pinging them.
+    let reply = poise::CreateReply::default()
+        .content(message)
+        .allowed_mentions(serenity::CreateAllowedMentions::new());
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+/// Deletes a saved playlist.
+#[poise::command(slash_command, prefix_command, rename = "delete")]
+async fn playlist_delete(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist."] name: String,
+    #[description = "One of your personal playlists."] personal: Option<bool>,
+) -> Result<(), Error> {
+    let deleted = ctx.data().playlists.delete(
+        playlist_scope(ctx, personal),
+        &name,
+        ctx.author().id,
+        is_admin(ctx).await,
+    );
+    match deleted {
+        Ok(()) => ctx.say(format!("*Deleted* **{}**.", name)),
+        Err(e) => ctx.say(e.to_string()),
+    }
+    .await?;
+    Ok(())
+}
+
+/// Adds a song to a saved playlist, creating it if needed.
+#[poise::command(slash_command, prefix_command, rename = "add")]
+async fn playlist_add(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist."] name: String,
+    #[description = "Url or title"] query: String,
+    #[description = "One of your personal playlists."] personal: Option<bool>,
+) -> Result<(), Error> {
+    let track = SavedTrack {
+        title: query.clone(),
+        query,
+    };
+    let added = ctx.data().playlists.add(
+        playlist_scope(ctx, personal),
+        &name,
+        ctx.author().id,
+        is_admin(ctx).await,
+        track,
+    );
+    match added {
+        Ok(count) => ctx.say(format!("*Added to* **{}**, *now {} tracks.*", name, count)),
+        Err(e) => ctx.say(e.to_string()),
+    }
+    .await?;
+    Ok(())
+}
+
+fn playlist_scope(ctx: Context<'_>, personal: Option<bool>) -> PlaylistScope {
+    if personal.unwrap_or(false) {
+        PlaylistScope::User(ctx.author().id)
+    } else {
+        PlaylistScope::Guild(ctx.guild_id().unwrap())
+    }
+}
+
+/// Whether the author administers the server, only known for slash commands.
+async fn is_admin(ctx: Context<'_>) -> bool {
+    ctx.author_member()
+        .await
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.administrator())
+}
+
+/// Saves the queue to a file or enqueues the songs of one.
+#[poise::command(
+    slash_command,
+    prefix_command,
+    subcommands("queue_export", "queue_import"),
+    subcommand_required
+)]
+async fn queue(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Sends the queue as a file, from the current song to the last.
+#[poise::command(slash_command, prefix_command, rename = "export")]
+async fn queue_export(
+    ctx: Context<'_>,
+    #[description = "m3u (default), json (with every detail) or txt (one link per line)."]
+    format: Option<ExportFormat>,
+) -> Result<(), Error> {
+    let entries = match general::existing_player(&ctx.guild_id().unwrap(), ctx.data()).await {
+        Some(player) => player.snapshot().entries,
+        None => Vec::new(),
+    };
+    if entries.is_empty() {
+        ctx.say("No songs queued.").await?;
+        return Ok(());
+    }
+
+    let format = format.unwrap_or_default();
+    let content = queue_file::export(&entries, format);
+    let file = serenity::CreateAttachment::bytes(
+        content.into_bytes(),
+        format!("queue.{}", format.extension()),
+    );
+    let reply = poise::CreateReply::default()
+        .content(format!("*Exported {} tracks.*", entries.len()))
+        .attachment(file);
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+/// Enqueues the songs of an exported queue file.
+#[poise::command(slash_command, prefix_command, rename = "import")]
+async fn queue_import(
+    ctx: Context<'_>,
+    #[description = "An m3u, json or txt file made by /queue export."] file: serenity::Attachment,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+
+    if file.size > MAX_IMPORT_BYTES {
+        ctx.say("The file is too large.").await?;
+        return Ok(());
+    }
+    let content = match file.download().await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            eprintln!("Failed to download {}: {}", file.filename, e);
+            ctx.say("Could not download the file.").await?;
+            return Ok(());
+        }
+    };
+    let queries = match queue_file::import(&file.filename, &content) {
+        Ok(queries) => queries,
+        Err(e) => {
+            ctx.say(e.to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let limit = ctx
+        .data()
+        .settings
+        .get(ctx.guild_id().unwrap())
+        .playlist_limit;
+    general::play_queries(
+        ctx.serenity_context(),
+        queries,
+        limit,
+        ctx.guild_id().unwrap(),
+        ctx.channel_id(),
+        &ctx.author().id,
+        ctx.data(),
+    )
+    .await;
+
+    ctx.say("Done").await?;
+    Ok(())
+}
+
+/// Shows or sets what is repeated once a track ends.
+#[poise::command(slash_command, prefix_command, rename = "loop")]
+async fn loop_mode(
+    ctx: Context<'_>,
+    #[description = "off, track (repeat the current track) or queue (repeat the whole queue)."]
+    mode: Option<LoopMode>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    let mode = match mode {
+        Some(mode) => {
+            match general::existing_player(&guild_id, ctx.data()).await {
+                Some(player) => player.set_loop(mode),
+                None => ctx
+                    .data()
+                    .settings
+                    .update(guild_id, |settings| settings.loop_mode = mode),
+            }
+            mode
+        }
+        None => ctx.data().settings.get(guild_id).loop_mode,
+    };
+
+    ctx.say(format!("*Loop:* **{}**.", mode)).await?;
+    Ok(())
+}
+
+/// Forgets the YouTube match cached for a Spotify track, so it is searched again.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn uncache(
+    ctx: Context<'_>,
+    #[description = "Spotify track or episode link, or its ID."] track: String,
+) -> Result<(), Error> {
+    let track_id = match spotify::parse_link(&track) {
+        Some(spotify::SpotifyLink::Track(id) | spotify::SpotifyLink::Episode(id)) => id,
+        _ => track.trim(),
+    };
+
+    if ctx.data().match_cache.remove(track_id) {
+        ctx.say(format!("*Forgot the match of:* **{}**.", track_id))
+            .await?;
+    } else {
+        ctx.say("No match cached for this track.").await?;
+    }
+    Ok(())
+}
+
+async fn event_handler(
+    ctx: &serenity::Context,
+    event: &serenity::FullEvent,
+    _framework: poise::FrameworkContext<'_, Data, Error>,
+    data: &Data,
+) -> Result<(), Error> {
+    if let serenity::FullEvent::VoiceStateUpdate { new, .. } = event {
+        data.inactivity.on_voice_state_update(ctx, new).await;
+    }
+    Ok(())
+}
+
 async fn update_yt_dlp() {
     let (yt_dlp_filename, url) = if cfg!(target_os = "windows") {
         (
@@ -298,8 +1228,9 @@ async fn main() {
             .expect("SPOTIFY_TOKE err in .env")
             .as_str(),
     );
-    let spotify_client = ClientCredsSpotify::new(spoty_cred);
-    spotify_client.request_token().await.unwrap();
+    let spotify = Arc::new(SpotifyClient::from_env(spoty_cred));
+    // Not fatal: the token is requested again on the first Spotify link.
+    let _ = spotify.refresh_token().await;
 
     let intents = serenity::GatewayIntents::non_privileged();
 
@@ -307,6 +1238,8 @@ async fn main() {
         .options(poise::FrameworkOptions {
             commands: vec![
                 play(),
+                playnext(),
+                playnow(),
                 resume(),
                 skip(),
                 pause(),
@@ -315,20 +1248,89 @@ async fn main() {
                 seek(),
                 list(),
                 remove(),
+                move_track(),
+                swap(),
+                shuffle(),
+                skipto(),
+                idle(),
+                playlistlimit(),
+                loop_mode(),
+                volume(),
+                ducking(),
+                crossfade(),
+                normalize(),
+                filter(),
+                history(),
+                playlist(),
+                queue(),
+                previous(),
+                replay(),
+                uncache(),
             ],
+            event_handler: |ctx, event, framework, data| {
+                Box::pin(event_handler(ctx, event, framework, data))
+            },
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+
+                let players = Arc::new(Mutex::new(HashMap::new()));
+                let settings = Arc::new(SettingsStore::load(SETTINGS_PATH));
+                let clips = Clips::default();
+                let inactivity =
+                    InactivitySupervisor::new(players.clone(), settings.clone(), clips.clone());
+                inactivity.start(ctx.clone());
+
+                let http_client = HttpClient::new();
+                let match_cache = Arc::new(MatchCache::load(MATCH_CACHE_PATH));
+                let history = Arc::new(History::load(HISTORY_PATH));
+                let youtube: Arc<dyn YoutubeBackend> = Arc::new(YtDlp::new(http_client.clone()));
+                let resolvers = Resolvers::new()
+                    .register(YoutubePlaylistResolver::new(youtube.clone()))
+                    .register(SpotifyResolver::new(
+                        spotify,
+                        youtube.clone(),
+                        match_cache.clone(),
+                    ))
+                    .register(HttpResolver::new(http_client.clone()))
+                    .register(LocalFileResolver::new(MUSIC_PATH))
+                    .register(YoutubeResolver::new(youtube));
+
+                let soundboard_data = soundboard::get_soundboard_data(AUDIO_PATH)
+                    .expect("Failed to load soundboard data");
+                // Files are measured in the background, they play as they are until then.
+                let clip_loudness = Arc::new(ClipLoudness::load(CLIP_LOUDNESS_PATH));
+                let names = soundboard_data
+                    .iter()
+                    .map(|(_, _, file_name)| file_name.clone())
+                    .collect::<Vec<_>>();
+                let measured = clip_loudness.clone();
+                tokio::task::spawn_blocking(move || {
+                    measured.analyse_all(Path::new(AUDIO_PATH), &names)
+                });
+
                 Ok(Data {
-                    last_interaction: Arc::new(Mutex::new(Instant::now())),
-                    soundboard_data: soundboard::get_soundboard_data(AUDIO_PATH)
-                        .expect("Failed to load soundboard data"),
-                    tracks: Arc::new(Mutex::new(HashMap::new())),
-                    spotify_client,
-                    playlist_cancellation: Mutex::new(HashMap::new()),
-                    reqwest_client: reqwest::Client::new(),
+                    inactivity,
+                    settings,
+                    soundboard_data,
+                    clips,
+                    clip_loudness,
+                    players,
+                    resolvers,
+                    lookahead: env::var("LOOKAHEAD_TRACKS")
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(DEFAULT_LOOKAHEAD_TRACKS),
+                    playlist_concurrency: env::var("PLAYLIST_CONCURRENCY")
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(DEFAULT_PLAYLIST_CONCURRENCY),
+                    match_cache,
+                    history,
+                    playlists: PlaylistStore::load(PLAYLISTS_PATH),
+                    reqwest_client: http_client,
                 })
             })
         })
@@ -337,7 +1339,6 @@ async fn main() {
     let client = serenity::ClientBuilder::new(token, intents)
         .framework(framework)
         .register_songbird()
-        .type_map_insert::<HttpKey>(HttpClient::new())
         .await;
     client.unwrap().start().await.unwrap();
 }
@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use songbird::input::AuxMetadata;
+
+use crate::settings::JsonWriter;
+
+const TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CAPACITY: usize = 5000;
+
+/// The YouTube video a Spotify track was resolved to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedMatch {
+    url: String,
+    title: Option<String>,
+    duration_ms: Option<u64>,
+    thumbnail: Option<String>,
+    resolved_at: u64,
+}
+
+struct CacheState {
+    entries: HashMap<String, CachedMatch>,
+    dirty: bool,
+}
+
+/// On-disk cache of Spotify track ID to YouTube match, so known tracks skip
+/// both the Spotify API and the YouTube search.
+pub struct MatchCache {
+    writer: JsonWriter<HashMap<String, CachedMatch>>,
+    state: Mutex<CacheState>,
+}
+
+impl MatchCache {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring malformed match cache {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        MatchCache {
+            writer: JsonWriter::new(path),
+            state: Mutex::new(CacheState {
+                entries,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// The cached match of a Spotify track, expired entries are dropped on the way.
+    pub fn get(&self, track_id: &str) -> Option<AuxMetadata> {
+        let mut state = self.state.lock().unwrap();
+        let cached = state.entries.get(track_id)?;
+
+        if now_secs().saturating_sub(cached.resolved_at) > TTL.as_secs() {
+            state.entries.remove(track_id);
+            state.dirty = true;
+            return None;
+        }
+
+        Some(AuxMetadata {
+            title: cached.title.clone(),
+            source_url: Some(cached.url.clone()),
+            duration: cached.duration_ms.map(Duration::from_millis),
+            thumbnail: cached.thumbnail.clone(),
+            ..AuxMetadata::default()
+        })
+    }
+
+    /// Remembers a match, evicting the oldest entries past the size cap.
+    /// Nothing is written until `flush`.
+    pub fn insert(&self, track_id: &str, metadata: &AuxMetadata) {
+        let Some(url) = metadata.source_url.clone() else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.entries.insert(
+            track_id.to_string(),
+            CachedMatch {
+                url,
+                title: metadata.title.clone(),
+                duration_ms: metadata.duration.map(|d| d.as_millis() as u64),
+                thumbnail: metadata.thumbnail.clone(),
+                resolved_at: now_secs(),
+            },
+        );
+
+        if state.entries.len() > CAPACITY {
+            let mut by_age: Vec<(u64, String)> = state
+                .entries
+                .iter()
+                .map(|(id, cached)| (cached.resolved_at, id.clone()))
+                .collect();
+            by_age.sort();
+
+            let excess = state.entries.len() - CAPACITY;
+            for (_, id) in by_age.into_iter().take(excess) {
+                state.entries.remove(&id);
+            }
+        }
+        state.dirty = true;
+    }
+
+    /// Forgets a match, returns whether there was one.
+    pub fn remove(&self, track_id: &str) -> bool {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let removed = state.entries.remove(track_id).is_some();
+            state.dirty |= removed;
+            removed
+        };
+        self.flush();
+        removed
+    }
+
+    /// Writes the cache file in the background if anything changed since the last write.
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.dirty {
+            return;
+        }
+
+        state.dirty = false;
+        self.writer.save(state.entries.clone());
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,209 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::all::{
+    ButtonStyle, ChannelId, CreateActionRow, CreateButton, CreateEmbed, CreateEmbedFooter,
+    CreateMessage, EditMessage, Http, MessageId,
+};
+
+use crate::{
+    player::LoopMode,
+    queue::{format_length, QueuedTrack},
+};
+
+const PROGRESS_WIDTH: usize = 20;
+
+/// Buttons of the now playing message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanelButton {
+    PauseResume,
+    Skip,
+    Previous,
+    Stop,
+    Shuffle,
+    Loop,
+}
+
+impl PanelButton {
+    const ALL: [PanelButton; 6] = [
+        PanelButton::Previous,
+        PanelButton::PauseResume,
+        PanelButton::Skip,
+        PanelButton::Stop,
+        PanelButton::Shuffle,
+        PanelButton::Loop,
+    ];
+
+    fn custom_id(self) -> &'static str {
+        match self {
+            PanelButton::PauseResume => "panel_pause",
+            PanelButton::Skip => "panel_skip",
+            PanelButton::Previous => "panel_previous",
+            PanelButton::Stop => "panel_stop",
+            PanelButton::Shuffle => "panel_shuffle",
+            PanelButton::Loop => "panel_loop",
+        }
+    }
+
+    pub fn from_custom_id(custom_id: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|button| button.custom_id() == custom_id)
+    }
+}
+
+/// What the now playing message shows.
+pub struct PanelView {
+    pub current: Option<QueuedTrack>,
+    pub position: Duration,
+    pub paused: bool,
+    /// Tracks after the current one.
+    pub upcoming: usize,
+    pub loop_mode: LoopMode,
+    pub shuffle: bool,
+}
+
+impl PanelView {
+    fn embed(&self) -> CreateEmbed {
+        let Some(current) = &self.current else {
+            return CreateEmbed::new()
+                .title("Nothing is playing")
+                .field("Loop", self.loop_mode.to_string(), true)
+                .field("Shuffle", on_off(self.shuffle), true);
+        };
+
+        let mut embed = CreateEmbed::new()
+            .title(current.title.chars().take(256).collect::<String>())
+            .description(progress_bar(self.position, current.duration))
+            .field("Requested by", format!("<@{}>", current.requester), true)
+            .field("Up next", format!("{} tracks", self.upcoming), true)
+            .field("Loop", self.loop_mode.to_string(), true)
+            .field("Shuffle", on_off(self.shuffle), true);
+
+        if let Some(url) = &current.url {
+            embed = embed.url(url);
+        }
+        if let Some(thumbnail) = &current.thumbnail {
+            embed = embed.thumbnail(thumbnail);
+        }
+        if self.paused {
+            embed = embed.footer(CreateEmbedFooter::new("Paused"));
+        }
+        embed
+    }
+
+    fn buttons(&self) -> Vec<CreateActionRow> {
+        let idle = self.current.is_none();
+        let toggle_style = |on: bool| {
+            if on {
+                ButtonStyle::Success
+            } else {
+                ButtonStyle::Secondary
+            }
+        };
+
+        let buttons = PanelButton::ALL
+            .into_iter()
+            .map(|button| {
+                let created = CreateButton::new(button.custom_id());
+                match button {
+                    PanelButton::PauseResume => created
+                        .label(if self.paused { "Resume" } else { "Pause" })
+                        .style(ButtonStyle::Primary)
+                        .disabled(idle),
+                    PanelButton::Skip => created
+                        .label("Skip")
+                        .style(ButtonStyle::Primary)
+                        .disabled(idle),
+                    PanelButton::Previous => created.label("Previous").style(ButtonStyle::Primary),
+                    PanelButton::Stop => created
+                        .label("Stop")
+                        .style(ButtonStyle::Danger)
+                        .disabled(idle),
+                    PanelButton::Shuffle => {
+                        created.label("Shuffle").style(toggle_style(self.shuffle))
+                    }
+                    PanelButton::Loop => created
+                        .label(format!("Loop: {}", self.loop_mode))
+                        .style(toggle_style(self.loop_mode != LoopMode::Off)),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Discord allows at most 5 buttons per row.
+        buttons
+            .chunks(5)
+            .map(|row| CreateActionRow::Buttons(row.to_vec()))
+            .collect()
+    }
+}
+
+/// The single now playing message of a guild, edited in place.
+pub struct Panel {
+    http: Arc<Http>,
+    message: Option<(ChannelId, MessageId)>,
+}
+
+impl Panel {
+    pub fn new(http: Arc<Http>) -> Self {
+        Panel {
+            http,
+            message: None,
+        }
+    }
+
+    /// Edits the message, or posts it in `channel_id` if there is none yet or it was deleted.
+    /// Returns the id of a newly posted message.
+    pub async fn show(&mut self, channel_id: ChannelId, view: &PanelView) -> Option<MessageId> {
+        if let Some((panel_channel, message_id)) = self.message {
+            let edit = EditMessage::new()
+                .embed(view.embed())
+                .components(view.buttons());
+            match panel_channel
+                .edit_message(&self.http, message_id, edit)
+                .await
+            {
+                Ok(_) => return None,
+                Err(e) => eprintln!("Failed to edit the now playing message: {}", e),
+            }
+        }
+
+        let message = CreateMessage::new()
+            .embed(view.embed())
+            .components(view.buttons());
+        match channel_id.send_message(&self.http, message).await {
+            Ok(message) => {
+                self.message = Some((channel_id, message.id));
+                Some(message.id)
+            }
+            Err(e) => {
+                eprintln!("Failed to send the now playing message: {}", e);
+                self.message = None;
+                None
+            }
+        }
+    }
+}
+
+fn progress_bar(position: Duration, length: Option<Duration>) -> String {
+    let Some(length) = length.filter(|length| !length.is_zero()) else {
+        return format!("`{}`", format_length(position));
+    };
+
+    let ratio = (position.as_secs_f64() / length.as_secs_f64()).min(1.0);
+    let filled = (ratio * PROGRESS_WIDTH as f64).round() as usize;
+    format!(
+        "{}{} `{} / {}`",
+        "▰".repeat(filled),
+        "▱".repeat(PROGRESS_WIDTH - filled),
+        format_length(position),
+        format_length(length)
+    )
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "On"
+    } else {
+        "Off"
+    }
+}
@@ -0,0 +1,613 @@
+use std::{
+    f64::consts::PI,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use songbird::input::{
+    codecs::{CODEC_REGISTRY, PROBE},
+    AudioStream,
+};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::{Decoder, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    units::{Time, TimeBase},
+};
+
+use crate::{
+    filters::{FilterChain, Filters},
+    loudness::{Normalization, Normalizer},
+    settings::GuildSettings,
+};
+
+/// Songbird's raw PCM header, followed by the sample rate and channel count.
+const RAW_MAGIC: &[u8; 8] = b"SbirdRaw";
+const HEADER_LEN: u64 = 16;
+const SAMPLE_LEN: u64 = std::mem::size_of::<f32>() as u64;
+
+/// Changes decoded audio before songbird plays it.
+pub trait Processor: Send + Sync {
+    /// `samples` are interleaved, `channels` per frame. Their number may change.
+    fn process(&mut self, samples: &mut Vec<f32>, channels: usize, sample_rate: u32);
+
+    /// The audio that follows a seek does not continue what came before.
+    fn reset(&mut self) {}
+
+    /// Frames taken in for every frame given out, above 1.0 when the audio is sped up.
+    fn rate(&self) -> f64 {
+        1.0
+    }
+}
+
+/// The processing a guild applies to its audio, shared with every stream playing
+/// so changes apply right away.
+#[derive(Clone)]
+pub struct Effects {
+    pub normalization: Normalization,
+    pub filters: Filters,
+}
+
+impl Effects {
+    pub fn new(settings: &GuildSettings) -> Self {
+        Effects {
+            normalization: Normalization::new(settings.normalization_target()),
+            filters: Filters::new(settings.filters.clone()),
+        }
+    }
+
+    /// Music is normalized first, so filters can still make it louder or quieter.
+    pub fn music(&self, stream: AudioStream<Box<dyn MediaSource>>) -> PcmStream {
+        PcmStream::new(
+            stream,
+            vec![
+                Box::new(Normalizer::new(self.normalization.clone())),
+                Box::new(FilterChain::new(self.filters.clone())),
+            ],
+        )
+    }
+
+    /// Soundboard clips get a fixed gain instead of normalization, see `ClipLoudness`.
+    pub fn clip(&self, stream: AudioStream<Box<dyn MediaSource>>) -> PcmStream {
+        PcmStream::new(
+            stream,
+            vec![Box::new(FilterChain::new(self.filters.clone()))],
+        )
+    }
+}
+
+/// A second order IIR filter, run on one channel.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// Coefficients normalized so `a0` is 1.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            ..Biquad::default()
+        }
+    }
+
+    /// From the coefficients of the Audio EQ Cookbook.
+    fn cookbook(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+
+    pub fn peaking(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w = 2.0 * PI * frequency / f64::from(sample_rate);
+        let alpha = w.sin() / (2.0 * q);
+        Biquad::cookbook(
+            [1.0 + alpha * a, -2.0 * w.cos(), 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * w.cos(), 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(sample_rate: u32, frequency: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w = 2.0 * PI * frequency / f64::from(sample_rate);
+        // Shelf slope of 1.
+        let alpha = w.sin() / 2.0 * 2f64.sqrt();
+        let (cos, root) = (w.cos(), 2.0 * a.sqrt() * alpha);
+        Biquad::cookbook(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + root,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    pub fn low_pass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let w = 2.0 * PI * frequency / f64::from(sample_rate);
+        let alpha = w.sin() / (2.0 * q);
+        let cos = w.cos();
+        Biquad::cookbook(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Takes the coefficients of `other`, keeping the state so the audio goes on without a click.
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Mixes surround sound down to stereo, songbird plays no more than two channels.
+struct Downmix {
+    /// How much of every source channel goes left and right.
+    weights: Vec<[f32; 2]>,
+}
+
+impl Downmix {
+    /// `None` when there are no more than two channels already.
+    fn new(channels: Channels) -> Option<Self> {
+        if channels.count() <= 2 {
+            return None;
+        }
+        let left = Channels::FRONT_LEFT
+            | Channels::REAR_LEFT
+            | Channels::FRONT_LEFT_CENTRE
+            | Channels::SIDE_LEFT
+            | Channels::TOP_FRONT_LEFT
+            | Channels::TOP_REAR_LEFT
+            | Channels::REAR_LEFT_CENTRE
+            | Channels::FRONT_LEFT_WIDE
+            | Channels::FRONT_LEFT_HIGH;
+        let right = Channels::FRONT_RIGHT
+            | Channels::REAR_RIGHT
+            | Channels::FRONT_RIGHT_CENTRE
+            | Channels::SIDE_RIGHT
+            | Channels::TOP_FRONT_RIGHT
+            | Channels::TOP_REAR_RIGHT
+            | Channels::REAR_RIGHT_CENTRE
+            | Channels::FRONT_RIGHT_WIDE
+            | Channels::FRONT_RIGHT_HIGH;
+        let side = std::f32::consts::FRAC_1_SQRT_2;
+
+        // The front pair as is, everything else 3dB down, and the bass channels left out.
+        let mut weights: Vec<[f32; 2]> = channels
+            .iter()
+            .map(|channel| {
+                if channel == Channels::FRONT_LEFT {
+                    [1.0, 0.0]
+                } else if channel == Channels::FRONT_RIGHT {
+                    [0.0, 1.0]
+                } else if left.contains(channel) {
+                    [side, 0.0]
+                } else if right.contains(channel) {
+                    [0.0, side]
+                } else if (Channels::LFE1 | Channels::LFE2).contains(channel) {
+                    [0.0, 0.0]
+                } else {
+                    [side, side]
+                }
+            })
+            .collect();
+        // Scaled so every channel at full scale does not clip.
+        let loudest = weights
+            .iter()
+            .fold([0.0f32; 2], |sum, weight| {
+                [sum[0] + weight[0], sum[1] + weight[1]]
+            })
+            .into_iter()
+            .fold(1.0f32, f32::max);
+        for weight in &mut weights {
+            *weight = weight.map(|weight| weight / loudest);
+        }
+        Some(Downmix { weights })
+    }
+
+    fn process(&self, samples: &[f32]) -> Vec<f32> {
+        samples
+            .chunks_exact(self.weights.len())
+            .flat_map(|frame| {
+                frame
+                    .iter()
+                    .zip(&self.weights)
+                    .fold([0.0; 2], |stereo, (sample, weight)| {
+                        [
+                            stereo[0] + sample * weight[0],
+                            stereo[1] + sample * weight[1],
+                        ]
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Part of the output played at one rate, sped up audio gets through its source faster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Span {
+    /// First frame given out.
+    output: u64,
+    /// Frame of the source it was made from.
+    source: u64,
+    rate: f64,
+}
+
+/// Maps frames given out to frames of the source, across speed changes.
+#[derive(Default)]
+struct Timeline {
+    spans: Vec<Span>,
+}
+
+impl Timeline {
+    /// The output from frame `output` on follows the source from `source` at `rate`.
+    fn record(&mut self, output: u64, source: u64, rate: f64) {
+        if self.spans.last().is_none_or(|span| span.rate != rate) {
+            self.spans.push(Span {
+                output,
+                source,
+                rate,
+            });
+        }
+    }
+
+    /// The source frame given out as frame `output`, at `rate` when nothing was yet.
+    fn source(&self, output: u64, rate: f64) -> u64 {
+        match self.spans.iter().rev().find(|span| span.output <= output) {
+            Some(span) => span.source + ((output - span.output) as f64 * span.rate) as u64,
+            None => (output as f64 * rate) as u64,
+        }
+    }
+
+    /// Forgets what was given out after frame `output`, before playing on from it.
+    fn rewind(&mut self, output: u64) {
+        self.spans.retain(|span| span.output <= output);
+    }
+}
+
+struct Decoding {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    /// Channels decoded, more than given out when they are mixed down.
+    channels: usize,
+    downmix: Option<Downmix>,
+}
+
+impl Decoding {
+    /// Channels given out.
+    fn output_channels(&self) -> usize {
+        if self.downmix.is_some() {
+            2
+        } else {
+            self.channels
+        }
+    }
+}
+
+/// Decodes a stream and hands its audio to songbird as raw PCM, through `processors` first.
+///
+/// Probing and decoding happen on the first read, so on songbird's blocking threads.
+pub struct PcmStream {
+    source: Option<AudioStream<Box<dyn MediaSource>>>,
+    decoding: Option<Decoding>,
+    processors: Vec<Box<dyn Processor>>,
+    seekable: bool,
+    /// Bytes ready to be read, the header and then samples.
+    buffer: Vec<u8>,
+    offset: usize,
+    /// Bytes read so far, as songbird sees them.
+    position: u64,
+    /// First frame to hand out after a seek, earlier ones are decoded and dropped.
+    skip_to: Option<u64>,
+    /// Source frame following the last one handed out.
+    frame: u64,
+    /// Frame following the last one handed out, as songbird counts them.
+    output: u64,
+    timeline: Timeline,
+}
+
+impl PcmStream {
+    pub fn new(
+        source: AudioStream<Box<dyn MediaSource>>,
+        processors: Vec<Box<dyn Processor>>,
+    ) -> Self {
+        PcmStream {
+            seekable: source.input.is_seekable(),
+            source: Some(source),
+            decoding: None,
+            processors,
+            buffer: Vec::new(),
+            offset: 0,
+            position: 0,
+            skip_to: None,
+            frame: 0,
+            output: 0,
+            timeline: Timeline::default(),
+        }
+    }
+
+    /// Wraps the stream for songbird, which recognises the raw header on its own.
+    pub fn into_stream(self) -> AudioStream<Box<dyn MediaSource>> {
+        AudioStream {
+            input: Box::new(self),
+            hint: None,
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        let source = self
+            .source
+            .take()
+            .ok_or_else(|| io::Error::other("the stream failed to start"))?;
+        let stream = MediaSourceStream::new(source.input, Default::default());
+        let probed = PROBE
+            .format(
+                &source.hint.unwrap_or_default(),
+                stream,
+                &FormatOptions {
+                    enable_gapless: true,
+                    ..Default::default()
+                },
+                &MetadataOptions::default(),
+            )
+            .map_err(io::Error::other)?;
+
+        let track = probed
+            .format
+            .default_track()
+            .ok_or_else(|| io::Error::other("no audio track"))?;
+        let params = &track.codec_params;
+        let sample_rate = params
+            .sample_rate
+            .ok_or_else(|| io::Error::other("unknown sample rate"))?;
+        let channels = params.channels.map_or(2, |channels| channels.count());
+        let downmix = params.channels.and_then(Downmix::new);
+        let decoder = CODEC_REGISTRY
+            .make(params, &DecoderOptions::default())
+            .map_err(io::Error::other)?;
+
+        let decoding = Decoding {
+            track_id: track.id,
+            time_base: params.time_base,
+            decoder,
+            format: probed.format,
+            sample_rate,
+            channels,
+            downmix,
+        };
+        self.buffer.extend_from_slice(RAW_MAGIC);
+        self.buffer.extend_from_slice(&sample_rate.to_le_bytes());
+        self.buffer
+            .extend_from_slice(&(decoding.output_channels() as u32).to_le_bytes());
+        self.decoding = Some(decoding);
+        Ok(())
+    }
+
+    /// Decodes the next packet into the buffer, `false` once the stream is over.
+    fn fill(&mut self) -> io::Result<bool> {
+        self.buffer.clear();
+        self.offset = 0;
+        let Some(decoding) = self.decoding.as_mut() else {
+            self.start()?;
+            return Ok(true);
+        };
+
+        loop {
+            let packet = match decoding.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(SymphoniaError::ResetRequired) => return Ok(false),
+                Err(e) => return Err(io::Error::other(e)),
+            };
+            if packet.track_id() != decoding.track_id {
+                continue;
+            }
+
+            let decoded = match decoding.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt packet is skipped, like songbird does.
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(io::Error::other(e)),
+            };
+            let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            samples.copy_interleaved_ref(decoded);
+            let samples = samples.samples_mut();
+            let frames = (samples.len() / decoding.channels) as u64;
+
+            let start = match decoding.time_base {
+                Some(time_base) => {
+                    let time = time_base.calc_time(packet.ts());
+                    ((time.seconds as f64 + time.frac) * decoding.sample_rate as f64) as u64
+                }
+                None => self.frame,
+            };
+            let skipped = match self.skip_to {
+                Some(target) if start + frames <= target => continue,
+                Some(target) => target.saturating_sub(start),
+                None => 0,
+            };
+            self.skip_to = None;
+            self.frame = start + frames;
+
+            let samples = &samples[skipped as usize * decoding.channels..];
+            let mut samples = match &decoding.downmix {
+                Some(downmix) => downmix.process(samples),
+                None => samples.to_vec(),
+            };
+            let channels = decoding.output_channels();
+            for processor in &mut self.processors {
+                processor.process(&mut samples, channels, decoding.sample_rate);
+            }
+            // The rate the processors just played this packet at.
+            let rate = self
+                .processors
+                .iter()
+                .map(|processor| processor.rate())
+                .product();
+            self.timeline.record(self.output, start + skipped, rate);
+            self.output += (samples.len() / channels) as u64;
+            self.buffer.reserve(samples.len() * SAMPLE_LEN as usize);
+            for sample in samples.iter() {
+                self.buffer.extend_from_slice(&sample.to_le_bytes());
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl Read for PcmStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.buffer.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.offset);
+        buf[..len].copy_from_slice(&self.buffer[self.offset..self.offset + len]);
+        self.offset += len;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for PcmStream {
+    /// Songbird only seeks to the start of a frame, past the header.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Current(0) => return Ok(self.position),
+            SeekFrom::Start(pos) if pos >= HEADER_LEN => pos,
+            _ => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        let decoding = self.decoding.as_mut().ok_or(io::ErrorKind::Unsupported)?;
+        // Songbird counts frames given out, sped up audio is further along its source.
+        let rate = self
+            .processors
+            .iter()
+            .map(|processor| processor.rate())
+            .product::<f64>();
+        let played = (pos - HEADER_LEN) / (SAMPLE_LEN * decoding.output_channels() as u64);
+        let target = self.timeline.source(played, rate);
+
+        if self.seekable {
+            let time = Time::from(target as f64 / decoding.sample_rate as f64);
+            decoding
+                .format
+                .seek(
+                    SeekMode::Accurate,
+                    SeekTo::Time {
+                        time,
+                        track_id: Some(decoding.track_id),
+                    },
+                )
+                .map_err(io::Error::other)?;
+            decoding.decoder.reset();
+        } else if target < self.frame {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        // Frames before the target are decoded and dropped.
+        self.skip_to = Some(target);
+        self.buffer.clear();
+        self.offset = 0;
+        self.position = pos;
+        self.output = played;
+        self.timeline.rewind(played);
+        for processor in &mut self.processors {
+            processor.reset();
+        }
+        Ok(pos)
+    }
+}
+
+impl MediaSource for PcmStream {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surround_sound_is_mixed_down_to_stereo() {
+        assert!(Downmix::new(Channels::FRONT_LEFT | Channels::FRONT_RIGHT).is_none());
+
+        let downmix = Downmix::new(
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::SIDE_LEFT
+                | Channels::SIDE_RIGHT,
+        )
+        .unwrap();
+        // Left only, then the centre, then the bass, then everything at full scale.
+        let stereo = downmix.process(&[
+            1.0, 0.0, 0.0, 0.0, 0.0, 0.0, //
+            0.0, 0.0, 1.0, 0.0, 0.0, 0.0, //
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, //
+            1.0, 1.0, 1.0, 1.0, 1.0, 1.0,
+        ]);
+        assert_eq!(stereo.len(), 8);
+        assert!(stereo[0] > 0.4 && stereo[1] == 0.0);
+        assert!(stereo[2] > 0.0 && stereo[2] == stereo[3]);
+        assert_eq!(&stereo[4..6], &[0.0, 0.0]);
+        assert!((stereo[6] - 1.0).abs() < 1e-6 && (stereo[7] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn seeks_follow_the_rate_each_part_was_played_at() {
+        let mut timeline = Timeline::default();
+        // 1000 frames at normal speed, then twice as fast.
+        timeline.record(0, 0, 1.0);
+        timeline.record(500, 500, 1.0);
+        timeline.record(1000, 1000, 2.0);
+        timeline.record(1500, 2000, 2.0);
+
+        assert_eq!(timeline.source(800, 2.0), 800);
+        assert_eq!(timeline.source(1200, 2.0), 1400);
+        assert_eq!(timeline.source(2000, 2.0), 3000);
+
+        // Back to the start, at the new speed from then on.
+        timeline.rewind(0);
+        timeline.record(0, 0, 2.0);
+        assert_eq!(timeline.source(800, 1.0), 1600);
+    }
+
+    #[test]
+    fn seeks_before_playing_use_the_current_rate() {
+        assert_eq!(Timeline::default().source(1000, 1.5), 1500);
+    }
+}
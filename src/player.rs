@@ -0,0 +1,1120 @@
+use std::{f32::consts::FRAC_PI_2, fmt, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use poise::ChoiceParameter;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    all::{
+        ChannelId, ComponentInteractionCollector, CreateInteractionResponse,
+        CreateInteractionResponseMessage, GuildId, Http, MessageId, ShardMessenger,
+    },
+    async_trait,
+};
+use songbird::{
+    tracks::{PlayMode, ReadyState, TrackCallback, TrackHandle},
+    Event, EventContext, EventHandler as VoiceEventHandler, Songbird, TrackEvent,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time::{self, Instant, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    history::History,
+    panel::{Panel, PanelButton, PanelView},
+    pcm::Effects,
+    queue::{GuildQueue, QueueEdit, QueueError, QueuedTrack, TrackSource},
+    seek::{SeekError, SeekTarget},
+    settings::{self, SettingsStore},
+};
+
+/// How often the progress bar of the now playing message moves.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// Changes are gathered for this long before the now playing message is edited.
+const PANEL_DEBOUNCE: Duration = Duration::from_millis(750);
+/// The music is raised back to its volume in this many steps once the clips end.
+const FADE_STEPS: u32 = 10;
+const FADE_STEP: Duration = Duration::from_millis(50);
+/// Longest wait before looking again how close the current track is to its end,
+/// as seeks and pauses move it.
+const CROSSFADE_CHECK: Duration = Duration::from_secs(5);
+/// How often an incoming track that is still loading is checked on.
+const CROSSFADE_RETRY: Duration = Duration::from_millis(500);
+/// Crossfades shorter than this, because the track ends too soon, are not started.
+const MIN_CROSSFADE: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopMode {
+    #[default]
+    #[name = "off"]
+    Off,
+    /// The current track plays over and over.
+    #[name = "track"]
+    Track,
+    /// Finished tracks go back to the end of the queue.
+    #[name = "queue"]
+    Queue,
+}
+
+impl LoopMode {
+    fn next(self) -> Self {
+        match self {
+            LoopMode::Off => LoopMode::Track,
+            LoopMode::Track => LoopMode::Queue,
+            LoopMode::Queue => LoopMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for LoopMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopMode::Off => write!(f, "Off"),
+            LoopMode::Track => write!(f, "Track"),
+            LoopMode::Queue => write!(f, "Queue"),
+        }
+    }
+}
+
+/// What happens to the music while a soundboard clip plays.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter)]
+#[serde(rename_all = "lowercase")]
+pub enum DuckMode {
+    /// Clips play over the music as it is.
+    #[name = "off"]
+    Off,
+    /// The music is lowered to the ducking level.
+    #[default]
+    #[name = "duck"]
+    Duck,
+    #[name = "pause"]
+    Pause,
+}
+
+impl fmt::Display for DuckMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuckMode::Off => write!(f, "Off"),
+            DuckMode::Duck => write!(f, "Duck"),
+            DuckMode::Pause => write!(f, "Pause"),
+        }
+    }
+}
+
+/// Read-only view of a guild queue, published by the player after every change.
+#[derive(Clone, Debug, Default)]
+pub struct QueueSnapshot {
+    /// Index 0 is the track currently playing.
+    pub entries: Vec<QueuedTrack>,
+    pub loop_mode: LoopMode,
+    pub shuffle: bool,
+}
+
+impl QueueSnapshot {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+enum PlayerCommand {
+    Enqueue {
+        tracks: Vec<(TrackSource, QueuedTrack)>,
+        channel_id: ChannelId,
+        token: Option<CancellationToken>,
+        placement: Placement,
+    },
+    NewBatch {
+        reply: oneshot::Sender<CancellationToken>,
+    },
+    Skip,
+    Pause,
+    Resume,
+    Panel(PanelButton),
+    SetLoop(LoopMode),
+    SetVolume(u16),
+    SetCrossfade(Duration),
+    /// A soundboard clip started with none playing.
+    Duck,
+    /// The last soundboard clip ended.
+    Unduck,
+    Previous {
+        reply: oneshot::Sender<bool>,
+    },
+    Seek {
+        target: SeekTarget,
+        channel_id: ChannelId,
+        reply: oneshot::Sender<Result<Seeking, SeekError>>,
+    },
+    Edit {
+        edit: QueueEdit,
+        reply: oneshot::Sender<Result<QueueEdited, QueueError>>,
+    },
+    Clear,
+    Refresh,
+    Ended(Uuid),
+}
+
+impl PlayerCommand {
+    /// Whether the command moves or replaces the tracks of a crossfade, which is called off first.
+    /// While `shuffle` is on, tracks added last are scattered and can land before the incoming one.
+    fn interrupts_crossfade(&self, shuffle: bool) -> bool {
+        matches!(
+            self,
+            PlayerCommand::Enqueue {
+                placement: Placement::Next | Placement::Now { .. },
+                ..
+            } | PlayerCommand::Panel(
+                PanelButton::Previous | PanelButton::Loop | PanelButton::Shuffle
+            ) | PlayerCommand::SetLoop(_)
+                | PlayerCommand::Previous { .. }
+                | PlayerCommand::Seek { .. }
+                | PlayerCommand::Edit { .. }
+        ) || shuffle
+            && matches!(
+                self,
+                PlayerCommand::Enqueue {
+                    placement: Placement::Last,
+                    ..
+                }
+            )
+    }
+}
+
+/// Where new tracks go in the queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    #[default]
+    Last,
+    /// Right after the current track.
+    Next,
+    /// Instead of the current track, which plays again afterwards from where it was if `resume`.
+    Now { resume: bool },
+}
+
+/// The queue right after an edit.
+pub struct QueueEdited {
+    pub removed: Vec<QueuedTrack>,
+    /// Index 0 is the track currently playing.
+    pub entries: Vec<QueuedTrack>,
+}
+
+/// How a seek is being carried out.
+enum Seeking {
+    /// The stream is moved, done once the callback resolves.
+    InPlace(TrackCallback<Duration>),
+    /// The track was loaded again, starting at the given position.
+    Restarted(Duration),
+}
+
+/// The current track fading out while the next one fades in.
+struct Crossfade {
+    outgoing: TrackHandle,
+    incoming: TrackHandle,
+    length: Duration,
+    /// Time faded so far, it stands still while paused.
+    elapsed: Duration,
+    paused: bool,
+}
+
+impl Crossfade {
+    /// Share of the music volume each track gets, equal power so the overlap is not louder.
+    fn share(&self, handle: &TrackHandle) -> f32 {
+        let progress = (self.elapsed.as_secs_f32() / self.length.as_secs_f32()).min(1.0);
+        let angle = progress * FRAC_PI_2;
+        if handle.uuid() == self.outgoing.uuid() {
+            angle.cos()
+        } else if handle.uuid() == self.incoming.uuid() {
+            angle.sin()
+        } else {
+            1.0
+        }
+    }
+}
+
+/// How queued tracks are loaded ahead of their turn.
+#[derive(Clone, Copy, Debug)]
+pub struct Prefetch {
+    /// How many tracks after the current one are loaded ahead of time.
+    pub lookahead: usize,
+    /// How many tracks are loaded at the same time.
+    pub concurrency: usize,
+}
+
+/// Cheap to clone handle to the task owning a guild's playback.
+///
+/// Every command is handled by that task in order, so a guild that is busy
+/// never makes another guild wait.
+#[derive(Clone)]
+pub struct PlayerHandle {
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+    snapshot: watch::Receiver<QueueSnapshot>,
+    effects: Effects,
+}
+
+struct Player {
+    guild_id: GuildId,
+    queue: GuildQueue,
+    manager: Arc<Songbird>,
+    http: Arc<Http>,
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+    snapshot: watch::Sender<QueueSnapshot>,
+    batch: CancellationToken,
+    /// How many tracks after the current one are loaded ahead of time.
+    lookahead: usize,
+    settings: Arc<SettingsStore>,
+    history: Arc<History>,
+    /// Kept in the guild settings, so it survives restarts.
+    loop_mode: LoopMode,
+    /// Tracks added while on go to a random place in the queue.
+    shuffle: bool,
+    /// Music volume, 1.0 is the original level.
+    volume: f32,
+    /// What was done to the music for the clips playing now.
+    ducked: Option<DuckMode>,
+    /// Steps done raising the music back after the clips ended.
+    fade: Option<u32>,
+    /// How long the end of a track overlaps the start of the next one, zero when off.
+    crossfade_length: Duration,
+    crossfade: Option<Crossfade>,
+    /// When to look again whether the current track is close enough to its end to crossfade.
+    crossfade_check: Option<Instant>,
+    shard: ShardMessenger,
+    panel: Panel,
+    /// Where the now playing message goes, the channel tracks were last added from.
+    panel_channel: Option<ChannelId>,
+    /// When to edit the now playing message, set by the first change since the last edit.
+    panel_due: Option<Instant>,
+    /// Stops listening to the buttons of a now playing message that was replaced.
+    panel_listener: Option<CancellationToken>,
+}
+
+struct RefreshNotifier {
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+}
+
+struct EndNotifier {
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+}
+
+/// Tells the channel a track was skipped because it could not be loaded.
+struct LoadFailureNotifier {
+    chan_id: ChannelId,
+    http: Arc<Http>,
+    title: String,
+}
+
+#[async_trait]
+impl VoiceEventHandler for LoadFailureNotifier {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(track_list) = ctx {
+            for (state, _) in track_list.iter() {
+                if let PlayMode::Errored(e) = &state.playing {
+                    eprintln!("Failed to load '{}': {}", self.title, e);
+                }
+            }
+        }
+
+        let message = format!("**Skipped:** {} (could not be loaded).", self.title);
+        if let Err(err) = self.chan_id.say(&self.http, message).await {
+            eprintln!("Failed to send message: {}", err);
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for RefreshNotifier {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let _ = self.commands.send(PlayerCommand::Refresh);
+        None
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for EndNotifier {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(track_list) = ctx {
+            for (_, handle) in track_list.iter() {
+                let _ = self.commands.send(PlayerCommand::Ended(handle.uuid()));
+            }
+        }
+        None
+    }
+}
+
+impl PlayerHandle {
+    /// Spawns the task owning playback for `guild_id`.
+    pub fn spawn(
+        guild_id: GuildId,
+        manager: Arc<Songbird>,
+        http: Arc<Http>,
+        shard: ShardMessenger,
+        settings: Arc<SettingsStore>,
+        history: Arc<History>,
+        prefetch: Prefetch,
+    ) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (snapshot_tx, snapshot) = watch::channel(QueueSnapshot::default());
+        let guild_settings = settings.get(guild_id);
+        let volume = settings::gain(guild_settings.music_volume);
+        let effects = Effects::new(&guild_settings);
+
+        let player = Player {
+            guild_id,
+            queue: GuildQueue::new(volume, effects.clone(), prefetch.concurrency),
+            manager,
+            panel: Panel::new(http.clone()),
+            http,
+            commands: commands.clone(),
+            snapshot: snapshot_tx,
+            batch: CancellationToken::new(),
+            lookahead: prefetch.lookahead,
+            loop_mode: guild_settings.loop_mode,
+            settings,
+            history,
+            shuffle: false,
+            volume,
+            ducked: None,
+            fade: None,
+            crossfade_length: Duration::from_secs(guild_settings.crossfade_secs),
+            crossfade: None,
+            crossfade_check: None,
+            shard,
+            panel_channel: None,
+            panel_due: None,
+            panel_listener: None,
+        };
+        tokio::spawn(player.run(receiver));
+
+        PlayerHandle {
+            commands,
+            snapshot,
+            effects,
+        }
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        self.snapshot.borrow().clone()
+    }
+
+    /// Adds tracks, in order. They are dropped if `token` was cancelled in the meantime.
+    pub fn enqueue(
+        &self,
+        tracks: Vec<(TrackSource, QueuedTrack)>,
+        channel_id: ChannelId,
+        token: Option<CancellationToken>,
+        placement: Placement,
+    ) {
+        self.send(PlayerCommand::Enqueue {
+            tracks,
+            channel_id,
+            token,
+            placement,
+        });
+    }
+
+    /// Token for a multi-track enqueue, cancelled by `clear`.
+    pub async fn new_batch(&self) -> CancellationToken {
+        let (reply, rx) = oneshot::channel();
+        self.send(PlayerCommand::NewBatch { reply });
+        rx.await.unwrap_or_else(|_| {
+            let token = CancellationToken::new();
+            token.cancel();
+            token
+        })
+    }
+
+    pub fn skip(&self) {
+        self.send(PlayerCommand::Skip);
+    }
+
+    pub fn pause(&self) {
+        self.send(PlayerCommand::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(PlayerCommand::Resume);
+    }
+
+    /// Plays the last finished track again, `false` if there is none since the bot started.
+    pub async fn previous(&self) -> bool {
+        let (reply, rx) = oneshot::channel();
+        self.send(PlayerCommand::Previous { reply });
+        rx.await.unwrap_or(false)
+    }
+
+    pub fn set_loop(&self, mode: LoopMode) {
+        self.send(PlayerCommand::SetLoop(mode));
+    }
+
+    /// Changes the music volume, in percent, of the queue and of the tracks added later.
+    pub fn set_volume(&self, percent: u16) {
+        self.send(PlayerCommand::SetVolume(percent));
+    }
+
+    /// Changes how long tracks overlap, in seconds, 0 turns crossfading off.
+    pub fn set_crossfade(&self, secs: u64) {
+        self.send(PlayerCommand::SetCrossfade(Duration::from_secs(secs)));
+    }
+
+    /// Normalization and filters of the guild, changes apply to what is playing.
+    pub fn effects(&self) -> &Effects {
+        &self.effects
+    }
+
+    /// Lowers or pauses the music, depending on the guild settings, until `unduck`.
+    pub fn duck(&self) {
+        self.send(PlayerCommand::Duck);
+    }
+
+    pub fn unduck(&self) {
+        self.send(PlayerCommand::Unduck);
+    }
+
+    /// Seeks the current track, returning the position it landed on.
+    pub async fn seek(
+        &self,
+        target: SeekTarget,
+        channel_id: ChannelId,
+    ) -> Result<Duration, SeekError> {
+        let (reply, rx) = oneshot::channel();
+        self.send(PlayerCommand::Seek {
+            target,
+            channel_id,
+            reply,
+        });
+
+        match rx.await.map_err(|_| SeekError::NothingPlaying)?? {
+            Seeking::InPlace(callback) => callback
+                .result_async()
+                .await
+                .map_err(|e| SeekError::Failed(e.to_string())),
+            Seeking::Restarted(position) => Ok(position),
+        }
+    }
+
+    pub async fn edit(&self, edit: QueueEdit) -> Result<QueueEdited, QueueError> {
+        let (reply, rx) = oneshot::channel();
+        self.send(PlayerCommand::Edit { edit, reply });
+        rx.await.map_err(|_| QueueError::Empty)?
+    }
+
+    /// Cancels any playlist being enqueued and empties the queue.
+    pub fn clear(&self) {
+        self.send(PlayerCommand::Clear);
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        if self.commands.send(command).is_err() {
+            eprintln!("Player task is gone, command dropped");
+        }
+    }
+}
+
+impl Player {
+    async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<PlayerCommand>) {
+        let mut progress = time::interval(PROGRESS_INTERVAL);
+        let mut fade_steps = time::interval(FADE_STEP);
+        fade_steps.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = receiver.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
+                    if command.interrupts_crossfade(self.shuffle) {
+                        self.cancel_crossfade();
+                    }
+                    self.handle(command).await;
+                    self.crossfade_check = Some(Instant::now());
+
+                    self.queue.preload(self.lookahead);
+                    if self.loop_mode == LoopMode::Track {
+                        if let Some(current) = self.queue.current() {
+                            let _ = current.enable_loop();
+                        }
+                    }
+                    self.publish();
+                    self.panel_changed();
+                }
+                _ = progress.tick() => {
+                    if self.queue.current().is_some() {
+                        self.panel_changed();
+                    }
+                }
+                _ = time::sleep_until(self.panel_due.unwrap_or_else(Instant::now)),
+                    if self.panel_due.is_some() => {
+                    self.update_panel().await;
+                }
+                // Shared by the unducking fade and the crossfade, which can run together.
+                _ = fade_steps.tick(), if self.fade.is_some() || self.crossfade.is_some() => {
+                    if self.fade.is_some() {
+                        self.fade =
+                            self.fade.map(|step| step + 1).filter(|step| *step < FADE_STEPS);
+                        self.apply_volume();
+                    }
+                    self.step_crossfade();
+                }
+                _ = time::sleep_until(self.crossfade_check.unwrap_or_else(Instant::now)),
+                    if self.crossfade.is_none() && self.crossfade_check.is_some() => {
+                    self.check_crossfade().await;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Enqueue {
+                tracks,
+                channel_id,
+                token,
+                placement,
+            } => {
+                if token.is_some_and(|token| token.is_cancelled()) {
+                    println!("Track addition cancelled.");
+                    return;
+                }
+                self.panel_channel = Some(channel_id);
+                self.enqueue(tracks, channel_id, placement).await;
+            }
+            PlayerCommand::NewBatch { reply } => {
+                let _ = reply.send(self.batch.child_token());
+            }
+            PlayerCommand::Skip => self.skip(),
+            PlayerCommand::Pause => self.pause(),
+            PlayerCommand::Resume => self.resume(),
+            PlayerCommand::Panel(button) => self.press(button).await,
+            PlayerCommand::SetLoop(mode) => self.set_loop(mode),
+            PlayerCommand::SetVolume(percent) => {
+                self.volume = settings::gain(percent);
+                self.apply_volume();
+            }
+            PlayerCommand::SetCrossfade(length) => self.crossfade_length = length,
+            PlayerCommand::Duck => self.duck().await,
+            PlayerCommand::Unduck => match self.ducked.take() {
+                Some(DuckMode::Duck) => self.fade = Some(0),
+                Some(DuckMode::Pause) => self.resume(),
+                _ => {}
+            },
+            PlayerCommand::Previous { reply } => {
+                let _ = reply.send(self.previous().await);
+            }
+            PlayerCommand::Seek {
+                target,
+                channel_id,
+                reply,
+            } => {
+                let _ = reply.send(self.seek(target, channel_id).await);
+            }
+            PlayerCommand::Edit { edit, reply } => {
+                let result = self.queue.edit(edit).map(|removed| QueueEdited {
+                    removed,
+                    entries: self.queue.entries(),
+                });
+                let _ = reply.send(result);
+            }
+            PlayerCommand::Clear => {
+                self.batch.cancel();
+                self.batch = CancellationToken::new();
+                self.crossfade = None;
+                self.queue.stop();
+            }
+            PlayerCommand::Refresh => {}
+            PlayerCommand::Ended(uuid) => {
+                // Skipped or ended on its own before the crossfade was over.
+                if self.crossfade.as_ref().is_some_and(|crossfade| {
+                    crossfade.outgoing.uuid() == uuid || crossfade.incoming.uuid() == uuid
+                }) {
+                    self.crossfade = None;
+                    self.apply_volume();
+                }
+                if let Some(entry) = self.queue.recent_entry(uuid) {
+                    self.history.record(self.guild_id, &entry);
+                }
+                if self.loop_mode == LoopMode::Queue {
+                    self.requeue(uuid).await;
+                }
+            }
+        }
+    }
+
+    async fn press(&mut self, button: PanelButton) {
+        match button {
+            PanelButton::PauseResume => {
+                let paused = match self.queue.current() {
+                    Some(current) => current
+                        .get_info()
+                        .await
+                        .is_ok_and(|state| matches!(state.playing, PlayMode::Pause)),
+                    None => return,
+                };
+                if paused {
+                    self.resume();
+                } else {
+                    self.pause();
+                }
+            }
+            PanelButton::Skip => self.skip(),
+            PanelButton::Previous => {
+                self.previous().await;
+            }
+            PanelButton::Stop => {
+                self.batch.cancel();
+                self.batch = CancellationToken::new();
+                self.crossfade = None;
+                self.queue.stop();
+            }
+            PanelButton::Shuffle => {
+                self.shuffle = !self.shuffle;
+                if self.shuffle {
+                    self.queue.shuffle();
+                }
+            }
+            PanelButton::Loop => self.set_loop(self.loop_mode.next()),
+        }
+    }
+
+    async fn duck(&mut self) {
+        if self.ducked.is_some() {
+            return;
+        }
+        self.fade = None;
+
+        let mode = match self.settings.get(self.guild_id).duck_mode {
+            DuckMode::Pause => {
+                let playing = match self.queue.current() {
+                    Some(current) => current
+                        .get_info()
+                        .await
+                        .is_ok_and(|state| matches!(state.playing, PlayMode::Play)),
+                    None => false,
+                };
+                // Music paused by hand stays paused once the clips end.
+                if playing {
+                    self.pause();
+                    DuckMode::Pause
+                } else {
+                    DuckMode::Off
+                }
+            }
+            mode => mode,
+        };
+        self.ducked = Some(mode);
+        self.apply_volume();
+    }
+
+    /// Sets the volume of the queue, the tracks of a crossfade get their share of it.
+    fn apply_volume(&self) {
+        let volume = self.music_volume();
+        match &self.crossfade {
+            Some(crossfade) => self
+                .queue
+                .set_volume(volume, |handle| crossfade.share(handle)),
+            None => self.queue.set_volume(volume, |_| 1.0),
+        }
+    }
+
+    fn pause(&mut self) {
+        self.queue.pause();
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.paused = true;
+            let _ = crossfade.incoming.pause();
+        }
+    }
+
+    fn resume(&mut self) {
+        self.queue.resume();
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.paused = false;
+            let _ = crossfade.incoming.play();
+        }
+    }
+
+    /// Skips the current track, during a crossfade the incoming one goes on at its full volume.
+    fn skip(&mut self) {
+        match self.crossfade.take() {
+            Some(crossfade) => self.finish_crossfade(crossfade),
+            None => self.queue.skip(),
+        }
+    }
+
+    /// Starts fading into the next track once the current one is close enough to its end,
+    /// otherwise works out when to look again.
+    async fn check_crossfade(&mut self) {
+        self.crossfade_check = None;
+        if self.crossfade_length.is_zero() || self.loop_mode == LoopMode::Track {
+            return;
+        }
+        let (Some(current), Some(next)) = (self.queue.current(), self.queue.next()) else {
+            return;
+        };
+        let Some(length) = self.queue.entry(&current).and_then(|entry| entry.duration) else {
+            return;
+        };
+        let Ok(state) = current.get_info().await else {
+            return;
+        };
+        // Resuming sends a command, which checks again.
+        if !matches!(state.playing, PlayMode::Play) {
+            return;
+        }
+
+        // Speed filters change how long the track plays for.
+        let rate = self.playback_rate();
+        let remaining = length.div_f64(rate).saturating_sub(state.position);
+        if remaining > self.crossfade_length {
+            let wait = (remaining - self.crossfade_length).min(CROSSFADE_CHECK);
+            self.crossfade_check = Some(Instant::now() + wait);
+            return;
+        }
+        if remaining < MIN_CROSSFADE {
+            return;
+        }
+
+        let ready = next
+            .get_info()
+            .await
+            .is_ok_and(|state| matches!(state.ready, ReadyState::Playable));
+        if !ready {
+            drop(next.make_playable());
+            self.crossfade_check = Some(Instant::now() + CROSSFADE_RETRY);
+            return;
+        }
+
+        let _ = next.play();
+        self.crossfade = Some(Crossfade {
+            outgoing: current,
+            incoming: next,
+            length: remaining,
+            elapsed: Duration::ZERO,
+            paused: false,
+        });
+        self.apply_volume();
+    }
+
+    /// How much faster than the original the music plays with the filters of the guild.
+    fn playback_rate(&self) -> f64 {
+        self.settings
+            .get(self.guild_id)
+            .filters
+            .resample_rate()
+            .max(f64::EPSILON)
+    }
+
+    fn step_crossfade(&mut self) {
+        let Some(crossfade) = &mut self.crossfade else {
+            return;
+        };
+        if !crossfade.paused {
+            crossfade.elapsed += FADE_STEP;
+        }
+        if crossfade.elapsed < crossfade.length {
+            self.apply_volume();
+        } else if let Some(crossfade) = self.crossfade.take() {
+            self.finish_crossfade(crossfade);
+        }
+    }
+
+    /// Stops the outgoing track, the incoming one is the current track from now on.
+    fn finish_crossfade(&mut self, crossfade: Crossfade) {
+        if self
+            .queue
+            .current()
+            .is_some_and(|current| current.uuid() == crossfade.outgoing.uuid())
+        {
+            self.queue.skip();
+        }
+        self.apply_volume();
+    }
+
+    /// Calls the crossfade off, the incoming track waits for its turn again.
+    fn cancel_crossfade(&mut self) {
+        if let Some(crossfade) = self.crossfade.take() {
+            // From the start when its turn comes, not from where the crossfade left it.
+            let _ = crossfade.incoming.pause();
+            let _ = crossfade.incoming.seek(Duration::ZERO);
+            self.apply_volume();
+        }
+    }
+
+    /// The volume the music should be at now, given the clips playing and the fade.
+    fn music_volume(&self) -> f32 {
+        let lowered = self.volume * settings::gain(self.settings.get(self.guild_id).duck_level);
+        match (self.ducked, self.fade) {
+            (Some(DuckMode::Duck), _) => lowered,
+            (_, Some(step)) => lowered + (self.volume - lowered) * step as f32 / FADE_STEPS as f32,
+            _ => self.volume,
+        }
+    }
+
+    fn set_loop(&mut self, mode: LoopMode) {
+        self.loop_mode = mode;
+        if mode != LoopMode::Track {
+            if let Some(current) = self.queue.current() {
+                let _ = current.disable_loop();
+            }
+        }
+        self.settings
+            .update(self.guild_id, |settings| settings.loop_mode = mode);
+    }
+
+    async fn previous(&self) -> bool {
+        let Some(handler_lock) = self.manager.get(self.guild_id) else {
+            return false;
+        };
+        let handle = {
+            let mut handler = handler_lock.lock().await;
+            self.queue.previous(&mut handler)
+        };
+        let found = handle.is_some();
+        self.watch_new(handle);
+        found
+    }
+
+    async fn requeue(&self, uuid: Uuid) {
+        let Some(handler_lock) = self.manager.get(self.guild_id) else {
+            return;
+        };
+        let handle = {
+            let mut handler = handler_lock.lock().await;
+            self.queue.requeue(uuid, &mut handler)
+        };
+        self.watch_new(handle);
+    }
+
+    /// Watches a track the queue created on its own, messages go to the panel channel.
+    fn watch_new(&self, handle: Option<TrackHandle>) {
+        let (Some(handle), Some(channel_id)) = (handle, self.panel_channel) else {
+            return;
+        };
+        if let Some(entry) = self.queue.entry(&handle) {
+            self.watch_track(&handle, channel_id, entry.title);
+        }
+    }
+
+    async fn enqueue(
+        &self,
+        tracks: Vec<(TrackSource, QueuedTrack)>,
+        channel_id: ChannelId,
+        placement: Placement,
+    ) {
+        let Some(handler_lock) = self.manager.get(self.guild_id) else {
+            println!("Not in a channel");
+            return;
+        };
+
+        let current = self.queue.current();
+        // Where the interrupted track was, to resume it from there.
+        let resume_at = match (&current, placement) {
+            (Some(current), Placement::Now { resume: true }) => current
+                .get_info()
+                .await
+                .map_or(Some(Duration::ZERO), |state| Some(state.position)),
+            _ => None,
+        };
+
+        // With nothing playing the first track starts right away at index 0.
+        let first = usize::from(current.is_some());
+        let count = tracks.len();
+        let mut handles = Vec::with_capacity(count + 1);
+        {
+            let mut handler = handler_lock.lock().await;
+            for (offset, (source, entry)) in tracks.into_iter().enumerate() {
+                handles.push(self.queue.enqueue(source, entry, &mut handler));
+                match placement {
+                    Placement::Last if self.shuffle => self.queue.scatter_last(),
+                    Placement::Last => {}
+                    Placement::Next | Placement::Now { .. } => {
+                        self.queue.move_last_to(first + offset)
+                    }
+                }
+            }
+
+            if current.is_some() {
+                if let Placement::Now { .. } = placement {
+                    match resume_at {
+                        Some(position) => {
+                            handles.extend(self.queue.copy_current(
+                                position,
+                                first + count,
+                                &mut handler,
+                            ));
+                            self.queue.drop_current();
+                        }
+                        None => self.queue.skip(),
+                    }
+                }
+            }
+        }
+
+        for handle in handles {
+            if let Some(entry) = self.queue.entry(&handle) {
+                self.watch_track(&handle, channel_id, entry.title);
+            }
+        }
+    }
+
+    async fn seek(&self, target: SeekTarget, channel_id: ChannelId) -> Result<Seeking, SeekError> {
+        let handle = self.queue.current().ok_or(SeekError::NothingPlaying)?;
+        let length = self.queue.entry(&handle).and_then(|entry| entry.duration);
+        let current = handle
+            .get_info()
+            .await
+            .map_err(|_| SeekError::NothingPlaying)?
+            .position;
+        let position = target.resolve(current, length)?;
+
+        if position >= current {
+            let callback = handle.seek(position);
+            let _ = handle.play();
+            return Ok(Seeking::InPlace(callback));
+        }
+
+        // A stream failing to seek back errors the track and skips it,
+        // so going back loads the track again and starts it at `position`.
+        let handler_lock = self
+            .manager
+            .get(self.guild_id)
+            .ok_or(SeekError::NothingPlaying)?;
+        let restarted = {
+            let mut handler = handler_lock.lock().await;
+            self.queue.restart_current(position, &mut handler)
+        };
+        let restarted = restarted.ok_or(SeekError::NothingPlaying)?;
+        if let Some(entry) = self.queue.entry(&restarted) {
+            self.watch_track(&restarted, channel_id, entry.title);
+        }
+
+        Ok(Seeking::Restarted(position))
+    }
+
+    fn watch_track(&self, track_handle: &TrackHandle, channel_id: ChannelId, title: String) {
+        let _ = track_handle.add_event(
+            Event::Track(TrackEvent::Error),
+            LoadFailureNotifier {
+                chan_id: channel_id,
+                http: self.http.clone(),
+                title,
+            },
+        );
+
+        let _ = track_handle.add_event(
+            Event::Track(TrackEvent::Play),
+            RefreshNotifier {
+                commands: self.commands.clone(),
+            },
+        );
+        let _ = track_handle.add_event(
+            Event::Track(TrackEvent::End),
+            EndNotifier {
+                commands: self.commands.clone(),
+            },
+        );
+    }
+
+    fn publish(&self) {
+        self.snapshot.send_replace(QueueSnapshot {
+            entries: self.queue.entries(),
+            loop_mode: self.loop_mode,
+            shuffle: self.shuffle,
+        });
+    }
+
+    /// Edits the now playing message shortly, along with the changes that follow.
+    fn panel_changed(&mut self) {
+        self.panel_due
+            .get_or_insert_with(|| Instant::now() + PANEL_DEBOUNCE);
+    }
+
+    async fn update_panel(&mut self) {
+        self.panel_due = None;
+        let Some(channel_id) = self.panel_channel else {
+            return;
+        };
+
+        let (current, position, paused) = match self.queue.current() {
+            Some(handle) => {
+                let state = handle.get_info().await.ok();
+                (
+                    self.queue.entry(&handle),
+                    state
+                        .as_ref()
+                        .map_or(Duration::ZERO, |state| state.position),
+                    state.is_some_and(|state| matches!(state.playing, PlayMode::Pause)),
+                )
+            }
+            None => (None, Duration::ZERO, false),
+        };
+        let view = PanelView {
+            current,
+            position,
+            paused,
+            upcoming: self.queue.entries().len().saturating_sub(1),
+            loop_mode: self.loop_mode,
+            shuffle: self.shuffle,
+        };
+
+        if let Some(message_id) = self.panel.show(channel_id, &view).await {
+            self.listen(message_id);
+        }
+    }
+
+    /// Turns presses on the buttons of the now playing message into commands.
+    fn listen(&mut self, message_id: MessageId) {
+        let token = CancellationToken::new();
+        if let Some(previous) = self.panel_listener.replace(token.clone()) {
+            previous.cancel();
+        }
+
+        let mut interactions = Box::pin(
+            ComponentInteractionCollector::new(&self.shard)
+                .message_id(message_id)
+                .stream(),
+        );
+        let commands = self.commands.clone();
+        let http = self.http.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let interaction = tokio::select! {
+                    _ = token.cancelled() => break,
+                    interaction = interactions.next() => match interaction {
+                        Some(interaction) => interaction,
+                        None => break,
+                    },
+                };
+
+                let is_admin = interaction
+                    .member
+                    .as_ref()
+                    .and_then(|member| member.permissions)
+                    .is_some_and(|permissions| permissions.administrator());
+                let response = match PanelButton::from_custom_id(&interaction.data.custom_id) {
+                    // Stopping empties the queue of everyone listening, like STOP ALL.
+                    Some(PanelButton::Stop) if !is_admin => CreateInteractionResponse::Message(
+                        CreateInteractionResponseMessage::new()
+                            .content("Only administrators can stop the music.")
+                            .ephemeral(true),
+                    ),
+                    Some(button) => {
+                        let _ = commands.send(PlayerCommand::Panel(button));
+                        CreateInteractionResponse::Acknowledge
+                    }
+                    None => CreateInteractionResponse::Acknowledge,
+                };
+                if let Err(e) = interaction.create_response(&http, response).await {
+                    eprintln!("Failed to acknowledge a button press: {}", e);
+                }
+            }
+        });
+    }
+}
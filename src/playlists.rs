@@ -0,0 +1,192 @@
+use std::{fmt, fs, path::Path, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, UserId};
+
+use crate::settings::JsonWriter;
+
+/// Who a saved playlist belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlaylistScope {
+    Guild(GuildId),
+    /// Personal playlists can be loaded in any server.
+    User(UserId),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedTrack {
+    pub title: String,
+    /// Given to the resolvers when the playlist is loaded.
+    pub query: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedPlaylist {
+    pub name: String,
+    pub scope: PlaylistScope,
+    /// The creator, the only one allowed to change it besides server admins.
+    pub owner: UserId,
+    pub tracks: Vec<SavedTrack>,
+}
+
+impl SavedPlaylist {
+    fn is(&self, scope: PlaylistScope, name: &str) -> bool {
+        self.scope == scope && self.name.eq_ignore_ascii_case(name)
+    }
+
+    fn can_change(&self, user: UserId, is_admin: bool) -> bool {
+        self.owner == user || (is_admin && matches!(self.scope, PlaylistScope::Guild(_)))
+    }
+}
+
+#[derive(Debug)]
+pub enum PlaylistError {
+    NotFound(String),
+    NotOwner(String),
+}
+
+impl fmt::Display for PlaylistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaylistError::NotFound(name) => write!(f, "There is no playlist named `{}`.", name),
+            PlaylistError::NotOwner(name) => write!(
+                f,
+                "Only the creator of `{}` or an administrator can change it.",
+                name
+            ),
+        }
+    }
+}
+
+/// Saved playlists of every guild and user, persisted as JSON.
+pub struct PlaylistStore {
+    writer: JsonWriter<Vec<SavedPlaylist>>,
+    playlists: Mutex<Vec<SavedPlaylist>>,
+}
+
+impl PlaylistStore {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let playlists = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring malformed playlists file {:?}: {}", path, e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        PlaylistStore {
+            writer: JsonWriter::new(path),
+            playlists: Mutex::new(playlists),
+        }
+    }
+
+    pub fn get(&self, scope: PlaylistScope, name: &str) -> Option<SavedPlaylist> {
+        self.playlists
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|playlist| playlist.is(scope, name))
+            .cloned()
+    }
+
+    /// Playlists of the guild and personal ones of the user.
+    pub fn list(&self, guild_id: GuildId, user: UserId) -> Vec<SavedPlaylist> {
+        self.playlists
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|playlist| {
+                playlist.scope == PlaylistScope::Guild(guild_id)
+                    || playlist.scope == PlaylistScope::User(user)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Creates the playlist, or replaces its tracks if `user` may change it.
+    pub fn save(
+        &self,
+        scope: PlaylistScope,
+        name: &str,
+        user: UserId,
+        is_admin: bool,
+        tracks: Vec<SavedTrack>,
+    ) -> Result<(), PlaylistError> {
+        self.change(scope, name, user, is_admin, |playlist| {
+            playlist.tracks = tracks
+        })
+    }
+
+    /// Appends a track, creating the playlist if needed. Returns its new length.
+    pub fn add(
+        &self,
+        scope: PlaylistScope,
+        name: &str,
+        user: UserId,
+        is_admin: bool,
+        track: SavedTrack,
+    ) -> Result<usize, PlaylistError> {
+        let mut len = 0;
+        self.change(scope, name, user, is_admin, |playlist| {
+            playlist.tracks.push(track);
+            len = playlist.tracks.len();
+        })?;
+        Ok(len)
+    }
+
+    pub fn delete(
+        &self,
+        scope: PlaylistScope,
+        name: &str,
+        user: UserId,
+        is_admin: bool,
+    ) -> Result<(), PlaylistError> {
+        let mut playlists = self.playlists.lock().unwrap();
+        let index = playlists
+            .iter()
+            .position(|playlist| playlist.is(scope, name))
+            .ok_or_else(|| PlaylistError::NotFound(name.to_string()))?;
+        if !playlists[index].can_change(user, is_admin) {
+            return Err(PlaylistError::NotOwner(name.to_string()));
+        }
+
+        playlists.remove(index);
+        self.writer.save(playlists.clone());
+        Ok(())
+    }
+
+    /// Applies `change` to the playlist, created for `user` if it does not exist.
+    fn change(
+        &self,
+        scope: PlaylistScope,
+        name: &str,
+        user: UserId,
+        is_admin: bool,
+        change: impl FnOnce(&mut SavedPlaylist),
+    ) -> Result<(), PlaylistError> {
+        let mut playlists = self.playlists.lock().unwrap();
+        let index = match playlists
+            .iter()
+            .position(|playlist| playlist.is(scope, name))
+        {
+            Some(index) if !playlists[index].can_change(user, is_admin) => {
+                return Err(PlaylistError::NotOwner(name.to_string()));
+            }
+            Some(index) => index,
+            None => {
+                playlists.push(SavedPlaylist {
+                    name: name.to_string(),
+                    scope,
+                    owner: user,
+                    tracks: Vec::new(),
+                });
+                playlists.len() - 1
+            }
+        };
+
+        change(&mut playlists[index]);
+        self.writer.save(playlists.clone());
+        Ok(())
+    }
+}
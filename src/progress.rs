@@ -0,0 +1,207 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::all::{ChannelId, EditMessage, Http, Message};
+use tokio::{sync::watch, time};
+use tokio_util::sync::CancellationToken;
+
+// Discord rate limits message edits, a couple of seconds between them is plenty.
+const PROGRESS_EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Counts {
+    /// Shown once every song was looked up, e.g. "Enqueued".
+    label: &'static str,
+    wanted: usize,
+    /// Songs in the playlist, more than `wanted` when it was cut short.
+    total: usize,
+    enqueued: usize,
+    loaded: usize,
+    failed: usize,
+    resolving: bool,
+    cancelled: bool,
+}
+
+impl Counts {
+    fn text(&self) -> String {
+        let mut text = if self.cancelled || self.resolving {
+            let status = if self.cancelled {
+                "Cancelled"
+            } else {
+                "Enqueuing"
+            };
+            format!("**{}:** {}/{}", status, self.enqueued, self.wanted)
+        } else {
+            format!("**{}:** [{}]", self.label, self.enqueued)
+        };
+        if self.wanted < self.total {
+            text.push_str(&format!(" of {} songs", self.total));
+        } else {
+            text.push_str(" songs");
+        }
+        if self.loaded > 0 {
+            text.push_str(&format!(", {} loaded", self.loaded));
+        }
+        if self.failed > 0 {
+            text.push_str(&format!(", {} failed", self.failed));
+        }
+        text.push('.');
+        text
+    }
+}
+
+/// Progress of a playlist being enqueued, shown in a single message that is edited in place.
+///
+/// Clones report to the same message, which is edited one last time once they are all gone.
+#[derive(Clone)]
+pub struct PlaylistProgress {
+    counts: Arc<watch::Sender<Counts>>,
+}
+
+impl PlaylistProgress {
+    /// Sends the progress message for `wanted` songs out of `total`, it shows "Cancelled"
+    /// once `token` is.
+    pub async fn start(
+        http: Arc<Http>,
+        channel_id: ChannelId,
+        label: &'static str,
+        wanted: usize,
+        total: usize,
+        token: CancellationToken,
+    ) -> Self {
+        let counts = Counts {
+            label,
+            wanted,
+            total: total.max(wanted),
+            resolving: true,
+            ..Counts::default()
+        };
+        let (sender, receiver) = watch::channel(counts.clone());
+
+        match channel_id.say(&http, counts.text()).await {
+            Ok(message) => {
+                tokio::spawn(keep_up_to_date(http, message, receiver, token));
+            }
+            Err(e) => eprintln!("Failed to send message: {}", e),
+        }
+
+        PlaylistProgress {
+            counts: Arc::new(sender),
+        }
+    }
+
+    /// `tracks` were added to the queue for one of the wanted songs, more than one
+    /// when it was a playlist itself.
+    pub fn enqueued(&self, tracks: usize) {
+        self.counts.send_modify(|counts| {
+            counts.enqueued += tracks;
+            counts.wanted = (counts.wanted + tracks).saturating_sub(1);
+            counts.total = counts.total.max(counts.wanted);
+        });
+    }
+
+    /// A queued track was loaded, ahead of its turn or when it started.
+    pub fn loaded(&self) {
+        self.counts.send_modify(|counts| counts.loaded += 1);
+    }
+
+    pub fn failed(&self) {
+        self.counts.send_modify(|counts| counts.failed += 1);
+    }
+
+    /// Every wanted song was looked up.
+    pub fn resolved(&self) {
+        self.counts.send_modify(|counts| counts.resolving = false);
+    }
+}
+
+/// Edits the message whenever the counts change, at most every `PROGRESS_EDIT_INTERVAL`.
+async fn keep_up_to_date(
+    http: Arc<Http>,
+    mut message: Message,
+    mut counts: watch::Receiver<Counts>,
+    token: CancellationToken,
+) {
+    let mut shown = message.content.clone();
+
+    loop {
+        let done = tokio::select! {
+            _ = token.cancelled() => {
+                let mut cancelled = counts.borrow().clone();
+                cancelled.cancelled = true;
+                edit(&http, &mut message, &mut shown, cancelled.text()).await;
+                return;
+            }
+            changed = counts.changed() => changed.is_err(),
+        };
+
+        let text = counts.borrow_and_update().text();
+        edit(&http, &mut message, &mut shown, text).await;
+        if done {
+            return;
+        }
+        time::sleep(PROGRESS_EDIT_INTERVAL).await;
+    }
+}
+
+async fn edit(http: &Http, message: &mut Message, shown: &mut String, text: String) {
+    if *shown == text {
+        return;
+    }
+    if let Err(e) = message.edit(http, EditMessage::new().content(&text)).await {
+        eprintln!("Failed to edit message: {}", e);
+    }
+    *shown = text;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(wanted: usize, total: usize) -> Counts {
+        Counts {
+            label: "Enqueued",
+            wanted,
+            total,
+            resolving: true,
+            ..Counts::default()
+        }
+    }
+
+    #[test]
+    fn shows_the_songs_enqueued_so_far() {
+        let mut counts = counts(10, 10);
+        counts.enqueued = 4;
+        assert_eq!(counts.text(), "**Enqueuing:** 4/10 songs.");
+
+        counts.total = 25;
+        counts.failed = 1;
+        assert_eq!(counts.text(), "**Enqueuing:** 4/10 of 25 songs, 1 failed.");
+    }
+
+    #[test]
+    fn shows_the_label_once_resolved() {
+        let mut counts = counts(3, 3);
+        counts.enqueued = 3;
+        counts.loaded = 2;
+        counts.resolving = false;
+        assert_eq!(counts.text(), "**Enqueued:** [3] songs, 2 loaded.");
+
+        counts.cancelled = true;
+        assert_eq!(counts.text(), "**Cancelled:** 3/3 songs, 2 loaded.");
+    }
+
+    #[test]
+    fn nested_playlists_add_to_the_wanted_songs() {
+        let (sender, _receiver) = watch::channel(counts(3, 3));
+        let progress = PlaylistProgress {
+            counts: Arc::new(sender),
+        };
+
+        progress.enqueued(1);
+        progress.enqueued(5);
+        progress.failed();
+        let counts = progress.counts.borrow().clone();
+        assert_eq!((counts.enqueued, counts.wanted, counts.total), (6, 7, 7));
+        assert_eq!(counts.failed, 1);
+    }
+}
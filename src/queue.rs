@@ -0,0 +1,667 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::{seq::SliceRandom, Rng};
+use serde::Serialize;
+use serenity::{
+    all::{Timestamp, UserId},
+    async_trait,
+};
+use songbird::{
+    events::EventData,
+    input::{AudioStream, AudioStreamError, AuxMetadata, Compose, Input, LiveInput},
+    tracks::{PlayMode, Track, TrackHandle, TrackQueue},
+    Driver, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
+};
+use symphonia::core::io::MediaSource;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::{pcm::Effects, progress::PlaylistProgress, resolver::TrackLoader};
+
+// Same lead time songbird uses when it computes the preload point itself.
+const PRELOAD_LEAD: Duration = Duration::from_secs(5);
+/// Longest overlap of a track with the next one, see `/crossfade`.
+pub const MAX_CROSSFADE_SECS: u64 = 12;
+/// How many ended tracks are kept to go back to.
+const RECENT_LEN: usize = 25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    YouTube,
+    Spotify,
+    Http,
+    LocalFile,
+}
+
+/// Everything we know about a queued track, shown by `/list` and the now playing message.
+#[derive(Clone, Debug)]
+pub struct QueuedTrack {
+    pub title: String,
+    pub url: Option<String>,
+    pub duration: Option<Duration>,
+    pub thumbnail: Option<String>,
+    pub requester: UserId,
+    pub kind: SourceKind,
+    /// When the track went into the queue, again when it is requeued.
+    pub enqueued_at: Timestamp,
+}
+
+impl QueuedTrack {
+    pub fn from_metadata(metadata: &AuxMetadata, requester: UserId, kind: SourceKind) -> Self {
+        QueuedTrack {
+            title: metadata
+                .title
+                .clone()
+                .unwrap_or_else(|| String::from("Unknown Title")),
+            url: metadata.source_url.clone(),
+            duration: metadata.duration,
+            thumbnail: metadata.thumbnail.clone(),
+            requester,
+            kind,
+            enqueued_at: Timestamp::now(),
+        }
+    }
+
+    /// Takes what loading the track found out, keeping the requester.
+    fn update(&mut self, metadata: &AuxMetadata) {
+        if let Some(title) = &metadata.title {
+            self.title = title.clone();
+        }
+        if metadata.source_url.is_some() {
+            self.url = metadata.source_url.clone();
+        }
+        if metadata.duration.is_some() {
+            self.duration = metadata.duration;
+        }
+        if metadata.thumbnail.is_some() {
+            self.thumbnail = metadata.thumbnail.clone();
+        }
+    }
+
+    pub fn url_or_placeholder(&self) -> &str {
+        self.url.as_deref().unwrap_or("URL not available")
+    }
+
+    /// One `/list` line: title, length and who asked for it.
+    pub fn describe(&self) -> String {
+        match self.duration {
+            Some(duration) => format!(
+                "**{}** `{}` <@{}>",
+                self.title,
+                format_length(duration),
+                self.requester
+            ),
+            None => format!("**{}** <@{}>", self.title, self.requester),
+        }
+    }
+}
+
+/// Formats a track length as `m:ss`, or `h:mm:ss` for long tracks.
+pub fn format_length(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Where the audio of a queued track comes from.
+pub struct TrackSource {
+    /// Kept for as long as the track is queued, so it can be loaded again.
+    loader: Arc<dyn TrackLoader>,
+    input: Option<Input>,
+    progress: Option<PlaylistProgress>,
+}
+
+impl TrackSource {
+    /// Loaded by songbird once the track is about to play, see `GuildQueue::preload`.
+    pub fn deferred(loader: Arc<dyn TrackLoader>) -> Self {
+        TrackSource {
+            loader,
+            input: None,
+            progress: None,
+        }
+    }
+
+    pub fn loaded(loader: Arc<dyn TrackLoader>, input: Input) -> Self {
+        TrackSource {
+            loader,
+            input: Some(input),
+            progress: None,
+        }
+    }
+
+    /// Tells `progress` whether the track could be loaded, the first time it is.
+    pub fn with_progress(mut self, progress: PlaylistProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+/// A queued track as the queue keeps it.
+#[derive(Clone)]
+struct Slot {
+    entry: QueuedTrack,
+    loader: Arc<dyn TrackLoader>,
+}
+
+type Slots = Arc<Mutex<HashMap<Uuid, Slot>>>;
+
+/// Runs the loader of a deferred track when songbird first needs its audio,
+/// and fills in the queue entry with the real metadata.
+struct DeferredInput {
+    loader: Arc<dyn TrackLoader>,
+    /// Given by the caller when the track was loaded before it was queued.
+    input: Option<Input>,
+    loaded: Option<Box<dyn Compose>>,
+    uuid: Uuid,
+    slots: Slots,
+    effects: Effects,
+    /// Shared by the tracks of the guild, so a long lookahead loads a few at a time.
+    loads: Arc<Semaphore>,
+    /// Dropped after the first load, so the progress message is done once every track was tried.
+    progress: Option<PlaylistProgress>,
+}
+
+impl DeferredInput {
+    /// Runs the audio through the effects of the guild, which may change while it plays.
+    fn process(
+        &self,
+        stream: AudioStream<Box<dyn MediaSource>>,
+    ) -> AudioStream<Box<dyn MediaSource>> {
+        self.effects.music(stream).into_stream()
+    }
+}
+
+#[async_trait]
+impl Compose for DeferredInput {
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        Err(AudioStreamError::Unsupported)
+    }
+
+    async fn create_async(
+        &mut self,
+    ) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+        // A backward seek recreates the stream, only the first call has to load.
+        if self.loaded.is_none() {
+            let input = match self.input.take() {
+                Some(input) => input,
+                None => {
+                    let permit = self.loads.acquire().await;
+                    let result = self.loader.load().await;
+                    drop(permit);
+
+                    if let Some(progress) = self.progress.take() {
+                        match &result {
+                            Ok(_) => progress.loaded(),
+                            Err(_) => progress.failed(),
+                        }
+                    }
+                    let loaded = result.map_err(|e| AudioStreamError::Fail(Box::new(e)))?;
+
+                    if let Some(slot) = self.slots.lock().unwrap().get_mut(&self.uuid) {
+                        slot.entry.update(&loaded.metadata);
+                    }
+                    loaded.input
+                }
+            };
+
+            match input {
+                Input::Lazy(compose) => self.loaded = Some(compose),
+                Input::Live(LiveInput::Raw(stream), _) => return Ok(self.process(stream)),
+                Input::Live(..) => return Err(AudioStreamError::Unsupported),
+            }
+        }
+
+        let compose = self.loaded.as_mut().ok_or(AudioStreamError::Unsupported)?;
+        let stream = if compose.should_create_async() {
+            compose.create_async().await
+        } else {
+            compose.create()
+        }?;
+        Ok(self.process(stream))
+    }
+
+    fn should_create_async(&self) -> bool {
+        true
+    }
+}
+
+/// A change to the order of the queue, indexes are the ones `/list` shows.
+#[derive(Clone, Debug)]
+pub enum QueueEdit {
+    Move {
+        from: usize,
+        to: usize,
+    },
+    Swap(usize, usize),
+    /// Shuffles every track after the current one.
+    Shuffle,
+    /// Drops the tracks before `index` and plays it.
+    SkipTo(usize),
+    Remove(RangeInclusive<usize>),
+    /// Removes every track added by the user.
+    RemoveBy(UserId),
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    Empty,
+    OutOfRange(usize),
+    /// The current track can only be skipped.
+    Current,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Empty => write!(f, "No songs queued."),
+            QueueError::OutOfRange(index) => write!(f, "There is no track {} in the queue.", index),
+            QueueError::Current => write!(f, "The track playing now can only be skipped."),
+        }
+    }
+}
+
+/// Songbird's `TrackQueue` plus the metadata and loader of every track in it.
+///
+/// The order always comes from songbird, metadata is looked up by track uuid,
+/// so what is listed is exactly what is going to be played.
+#[derive(Clone)]
+pub struct GuildQueue {
+    tracks: TrackQueue,
+    slots: Slots,
+    /// Tracks that finished or were skipped, oldest first.
+    recent: Arc<Mutex<VecDeque<(Uuid, Slot)>>>,
+    /// Given to every track, 1.0 is the original level.
+    volume: Arc<Mutex<f32>>,
+    effects: Effects,
+    loads: Arc<Semaphore>,
+}
+
+/// Moves the slot of an ended track to the recently played ones.
+struct TrackEndNotifier {
+    slots: Slots,
+    recent: Arc<Mutex<VecDeque<(Uuid, Slot)>>>,
+}
+
+#[async_trait]
+impl VoiceEventHandler for TrackEndNotifier {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(track_list) = ctx {
+            let mut slots = self.slots.lock().unwrap();
+            let mut recent = self.recent.lock().unwrap();
+            for (state, handle) in track_list.iter() {
+                let Some(slot) = slots.remove(&handle.uuid()) else {
+                    continue;
+                };
+                // Tracks that failed to load are not worth going back to.
+                if matches!(state.playing, PlayMode::Errored(_)) {
+                    continue;
+                }
+                remember(&mut recent, handle.uuid(), slot);
+            }
+        }
+        None
+    }
+}
+
+/// Checks that `index` is one of the tracks after the current one, in a queue of `len`.
+fn upcoming(index: usize, len: usize) -> Result<usize, QueueError> {
+    match index {
+        0 => Err(QueueError::Current),
+        index if index >= len => Err(QueueError::OutOfRange(index)),
+        index => Ok(index),
+    }
+}
+
+fn remember(recent: &mut VecDeque<(Uuid, Slot)>, uuid: Uuid, slot: Slot) {
+    if recent.len() == RECENT_LEN {
+        recent.pop_front();
+    }
+    recent.push_back((uuid, slot));
+}
+
+impl GuildQueue {
+    /// `concurrency` is how many tracks can be loading at the same time.
+    pub fn new(volume: f32, effects: Effects, concurrency: usize) -> Self {
+        GuildQueue {
+            tracks: TrackQueue::new(),
+            slots: Slots::default(),
+            recent: Arc::default(),
+            volume: Arc::new(Mutex::new(volume)),
+            effects,
+            loads: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    /// Appends a track to the queue, its slot is moved to the recent tracks once it ends.
+    pub fn enqueue(
+        &self,
+        source: TrackSource,
+        mut entry: QueuedTrack,
+        driver: &mut Driver,
+    ) -> TrackHandle {
+        entry.enqueued_at = Timestamp::now();
+        // Early enough for the longest crossfade to find the next track ready.
+        let lead = PRELOAD_LEAD + Duration::from_secs(MAX_CROSSFADE_SECS);
+        let preload_time = entry.duration.map(|d| d.saturating_sub(lead));
+        let uuid = Uuid::new_v4();
+        let input = Input::Lazy(Box::new(DeferredInput {
+            loader: source.loader.clone(),
+            input: source.input,
+            loaded: None,
+            uuid,
+            slots: self.slots.clone(),
+            effects: self.effects.clone(),
+            loads: self.loads.clone(),
+            progress: source.progress,
+        }));
+        let mut track = Track::new_with_uuid(input, uuid).volume(*self.volume.lock().unwrap());
+
+        track.events.add_event(
+            EventData::new(
+                Event::Track(TrackEvent::End),
+                TrackEndNotifier {
+                    slots: self.slots.clone(),
+                    recent: self.recent.clone(),
+                },
+            ),
+            Duration::ZERO,
+        );
+
+        // Holding the slots lock while adding means nobody can observe the
+        // track in the queue without its metadata.
+        let mut slots = self.slots.lock().unwrap();
+        let handle = self.tracks.add_with_preload(track, driver, preload_time);
+        slots.insert(
+            handle.uuid(),
+            Slot {
+                entry,
+                loader: source.loader,
+            },
+        );
+        handle
+    }
+
+    /// Replaces the current track with a freshly loaded copy starting at `position`,
+    /// for when its stream cannot seek there.
+    pub fn restart_current(&self, position: Duration, driver: &mut Driver) -> Option<TrackHandle> {
+        let handle = self.copy_current(position, 1, driver)?;
+        self.drop_current();
+        Some(handle)
+    }
+
+    /// Queues a freshly loaded copy of the current track at `index`, starting at `position`.
+    pub fn copy_current(
+        &self,
+        position: Duration,
+        index: usize,
+        driver: &mut Driver,
+    ) -> Option<TrackHandle> {
+        let current = self.tracks.current()?;
+        let slot = self.slots.lock().unwrap().get(&current.uuid()).cloned()?;
+
+        let handle = self.enqueue(TrackSource::deferred(slot.loader), slot.entry, driver);
+        if !position.is_zero() {
+            let _ = handle.seek(position);
+        }
+        self.move_last_to(index);
+        Some(handle)
+    }
+
+    /// Plays the most recent track again, the current one follows it from the start.
+    pub fn previous(&self, driver: &mut Driver) -> Option<TrackHandle> {
+        let (_, previous) = self.recent.lock().unwrap().pop_back()?;
+        let current = self
+            .tracks
+            .current()
+            .and_then(|handle| self.slots.lock().unwrap().get(&handle.uuid()).cloned());
+
+        let handle = self.enqueue(
+            TrackSource::deferred(previous.loader),
+            previous.entry,
+            driver,
+        );
+        if let Some(current) = current {
+            self.move_last_to(1);
+            self.enqueue(TrackSource::deferred(current.loader), current.entry, driver);
+            self.move_last_to(2);
+            self.drop_current();
+        }
+        Some(handle)
+    }
+
+    /// Appends a fresh copy of a track that ended, if it is still among the recent ones.
+    pub fn requeue(&self, uuid: Uuid, driver: &mut Driver) -> Option<TrackHandle> {
+        let slot = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(ended, _)| *ended == uuid)
+            .map(|(_, slot)| slot.clone())?;
+
+        Some(self.enqueue(TrackSource::deferred(slot.loader), slot.entry, driver))
+    }
+
+    /// The entry of a track that ended, if it is still among the recent ones.
+    pub fn recent_entry(&self, uuid: Uuid) -> Option<QueuedTrack> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(ended, _)| *ended == uuid)
+            .map(|(_, slot)| slot.entry.clone())
+    }
+
+    /// Shuffles the tracks after the current one.
+    pub fn shuffle(&self) {
+        self.tracks.modify_queue(|queue| {
+            if queue.len() > 2 {
+                queue.make_contiguous()[1..].shuffle(&mut rand::thread_rng());
+            }
+        });
+    }
+
+    /// Moves the track added last to a random place after the current one.
+    pub fn scatter_last(&self) {
+        let len = self.tracks.len();
+        if len > 2 {
+            self.move_last_to(rand::thread_rng().gen_range(1..len));
+        }
+    }
+
+    /// Moves the track added last to `index`.
+    pub fn move_last_to(&self, index: usize) {
+        self.tracks.modify_queue(|queue| {
+            if index < queue.len() {
+                if let Some(track) = queue.pop_back() {
+                    queue.insert(index, track);
+                }
+            }
+        });
+    }
+
+    /// Stops the current track without keeping it among the recent ones.
+    pub fn drop_current(&self) {
+        if let Some(current) = self.tracks.current() {
+            self.slots.lock().unwrap().remove(&current.uuid());
+        }
+        let _ = self.tracks.skip();
+    }
+
+    /// Snapshot of the queue, index 0 is the track currently playing.
+    pub fn entries(&self) -> Vec<QueuedTrack> {
+        let slots = self.slots.lock().unwrap();
+        self.tracks
+            .current_queue()
+            .iter()
+            .filter_map(|handle| slots.get(&handle.uuid()).map(|slot| slot.entry.clone()))
+            .collect()
+    }
+
+    pub fn entry(&self, handle: &TrackHandle) -> Option<QueuedTrack> {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(&handle.uuid())
+            .map(|slot| slot.entry.clone())
+    }
+
+    /// Applies `edit`, returning the tracks it removed.
+    pub fn edit(&self, edit: QueueEdit) -> Result<Vec<QueuedTrack>, QueueError> {
+        let len = self.tracks.len();
+        if len == 0 {
+            return Err(QueueError::Empty);
+        }
+
+        match edit {
+            QueueEdit::Move { from, to } => {
+                let (from, to) = (upcoming(from, len)?, upcoming(to, len)?);
+                self.tracks.modify_queue(|queue| {
+                    if let Some(track) = queue.remove(from) {
+                        queue.insert(to, track);
+                    }
+                });
+                Ok(Vec::new())
+            }
+            QueueEdit::Swap(a, b) => {
+                let (a, b) = (upcoming(a, len)?, upcoming(b, len)?);
+                self.tracks.modify_queue(|queue| queue.swap(a, b));
+                Ok(Vec::new())
+            }
+            QueueEdit::Shuffle => {
+                self.shuffle();
+                Ok(Vec::new())
+            }
+            QueueEdit::SkipTo(index) => {
+                let index = upcoming(index, len)?;
+                self.drop_where(|position, _| (1..index).contains(&position));
+                self.skip();
+                Ok(Vec::new())
+            }
+            QueueEdit::Remove(range) => {
+                upcoming(*range.start(), len)?;
+                upcoming(*range.end(), len)?;
+                Ok(self.drop_where(|position, _| range.contains(&position)))
+            }
+            QueueEdit::RemoveBy(user) => Ok(self.drop_where(|_, entry| entry.requester == user)),
+        }
+    }
+
+    /// Takes out every track matching `condition`, given its index and entry.
+    /// The removed tracks are not kept among the recent ones.
+    fn drop_where(&self, condition: impl Fn(usize, &QueuedTrack) -> bool) -> Vec<QueuedTrack> {
+        let mut slots = self.slots.lock().unwrap();
+        let is_dropped = |position: usize, handle: &TrackHandle| {
+            slots
+                .get(&handle.uuid())
+                .is_some_and(|slot| condition(position, &slot.entry))
+        };
+
+        let current_dropped = self
+            .tracks
+            .current()
+            .is_some_and(|current| is_dropped(0, &current));
+        let dropped = self.tracks.modify_queue(|queue| {
+            let mut position = 0;
+            let mut dropped = Vec::new();
+            queue.retain(|track| {
+                let keep = position == 0 || !is_dropped(position, &track.handle());
+                if !keep {
+                    dropped.push(track.handle());
+                }
+                position += 1;
+                keep
+            });
+            dropped
+        });
+
+        let mut removed = Vec::new();
+        if current_dropped {
+            if let Some(current) = self.tracks.current() {
+                removed.extend(slots.remove(&current.uuid()).map(|slot| slot.entry));
+                let _ = self.tracks.skip();
+            }
+        }
+        for handle in dropped {
+            removed.extend(slots.remove(&handle.uuid()).map(|slot| slot.entry));
+            let _ = handle.stop();
+        }
+        removed
+    }
+
+    /// Starts loading the `lookahead` tracks after the current one,
+    /// so they are ready by the time they play.
+    pub fn preload(&self, lookahead: usize) {
+        for handle in self.tracks.current_queue().iter().skip(1).take(lookahead) {
+            drop(handle.make_playable());
+        }
+    }
+
+    pub fn current(&self) -> Option<TrackHandle> {
+        self.tracks.current()
+    }
+
+    /// The track after the current one.
+    pub fn next(&self) -> Option<TrackHandle> {
+        self.tracks
+            .modify_queue(|queue| queue.get(1).map(|track| track.handle()))
+    }
+
+    /// Skips the current track, it is gone from the entries right away.
+    pub fn skip(&self) {
+        if let Some(current) = self.tracks.current() {
+            let slot = self.slots.lock().unwrap().remove(&current.uuid());
+            if let Some(slot) = slot {
+                remember(&mut self.recent.lock().unwrap(), current.uuid(), slot);
+            }
+        }
+        let _ = self.tracks.skip();
+    }
+
+    /// Changes the volume of every queued track and of the ones added later,
+    /// `share` scales it for the tracks that are fading.
+    pub fn set_volume(&self, volume: f32, share: impl Fn(&TrackHandle) -> f32) {
+        *self.volume.lock().unwrap() = volume;
+        for handle in self.tracks.current_queue() {
+            let _ = handle.set_volume(volume * share(&handle));
+        }
+    }
+
+    pub fn pause(&self) {
+        let _ = self.tracks.pause();
+    }
+
+    pub fn resume(&self) {
+        let _ = self.tracks.resume();
+    }
+
+    pub fn stop(&self) {
+        self.slots.lock().unwrap().clear();
+        self.tracks.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_upcoming_tracks_can_be_edited() {
+        assert!(matches!(upcoming(0, 5), Err(QueueError::Current)));
+        assert!(matches!(upcoming(1, 5), Ok(1)));
+        assert!(matches!(upcoming(4, 5), Ok(4)));
+        assert!(matches!(upcoming(5, 5), Err(QueueError::OutOfRange(5))));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{fmt, time::Duration};
+
+use poise::ChoiceParameter;
+use serde::{Deserialize, Serialize};
+use serenity::all::{Timestamp, UserId};
+
+use crate::queue::{QueuedTrack, SourceKind};
+
+/// Songs past this many in a file are left out.
+const MAX_SONGS: usize = 5000;
+
+/// File formats the queue can be exported to and imported from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ChoiceParameter)]
+pub enum ExportFormat {
+    #[default]
+    #[name = "m3u"]
+    M3u,
+    #[name = "json"]
+    Json,
+    #[name = "txt"]
+    Text,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::M3u => "m3u",
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+/// A queued track as written to a JSON export.
+#[derive(Debug, Serialize)]
+struct ExportedTrack<'a> {
+    title: &'a str,
+    url: Option<&'a str>,
+    /// In seconds.
+    duration: Option<u64>,
+    thumbnail: Option<&'a str>,
+    requester: UserId,
+    kind: SourceKind,
+    enqueued_at: Timestamp,
+}
+
+/// The fields of a JSON export needed to enqueue a track again.
+#[derive(Debug, Deserialize)]
+struct ImportedTrack {
+    title: Option<String>,
+    url: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Malformed(String),
+    Empty,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Malformed(e) => write!(f, "The file could not be read ({}).", e),
+            ImportError::Empty => write!(f, "The file has no songs in it."),
+        }
+    }
+}
+
+/// The queue written in `format`, from the current track to the last.
+pub fn export(entries: &[QueuedTrack], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::M3u => {
+            let mut content = String::from("#EXTM3U\n");
+            for entry in entries {
+                // -1 marks an unknown length.
+                let secs = entry
+                    .duration
+                    .map_or(-1, |duration| duration.as_secs() as i64);
+                content.push_str(&format!("#EXTINF:{},{}\n", secs, entry.title));
+                content.push_str(query(entry));
+                content.push('\n');
+            }
+            content
+        }
+        ExportFormat::Json => {
+            let tracks = entries
+                .iter()
+                .map(|entry| ExportedTrack {
+                    title: &entry.title,
+                    url: entry.url.as_deref(),
+                    duration: entry.duration.as_ref().map(Duration::as_secs),
+                    thumbnail: entry.thumbnail.as_deref(),
+                    requester: entry.requester,
+                    kind: entry.kind,
+                    enqueued_at: entry.enqueued_at,
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_string_pretty(&tracks).unwrap_or_default()
+        }
+        ExportFormat::Text => entries
+            .iter()
+            .map(|entry| format!("{}\n", query(entry)))
+            .collect(),
+    }
+}
+
+/// The songs of an exported queue, as queries for the resolvers.
+/// JSON is recognised by its extension or content, anything else is read one song per line.
+pub fn import(file_name: &str, content: &str) -> Result<Vec<String>, ImportError> {
+    let is_json =
+        file_name.to_lowercase().ends_with(".json") || content.trim_start().starts_with('[');
+
+    let queries = if is_json {
+        let tracks: Vec<ImportedTrack> =
+            serde_json::from_str(content).map_err(|e| ImportError::Malformed(e.to_string()))?;
+        tracks
+            .into_iter()
+            .filter_map(|track| track.url.or(track.title))
+            .take(MAX_SONGS)
+            .collect::<Vec<_>>()
+    } else {
+        // M3U directives and comments start with '#'.
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .take(MAX_SONGS)
+            .map(String::from)
+            .collect()
+    };
+
+    if queries.is_empty() {
+        return Err(ImportError::Empty);
+    }
+    Ok(queries)
+}
+
+/// Tracks without a link are searched by title.
+fn query(entry: &QueuedTrack) -> &str {
+    entry.url.as_deref().unwrap_or(&entry.title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(title: &str, url: Option<&str>, secs: Option<u64>) -> QueuedTrack {
+        QueuedTrack {
+            title: title.to_string(),
+            url: url.map(String::from),
+            duration: secs.map(Duration::from_secs),
+            thumbnail: None,
+            requester: UserId::new(1),
+            kind: SourceKind::YouTube,
+            enqueued_at: Timestamp::now(),
+        }
+    }
+
+    fn queue() -> Vec<QueuedTrack> {
+        vec![
+            entry("First", Some("https://youtu.be/first"), Some(200)),
+            entry("Second, with a comma", None, None),
+            entry(
+                "Third",
+                Some("https://open.spotify.com/track/3"),
+                Some(3700),
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        let expected = vec![
+            "https://youtu.be/first",
+            "Second, with a comma",
+            "https://open.spotify.com/track/3",
+        ];
+        for format in [ExportFormat::M3u, ExportFormat::Json, ExportFormat::Text] {
+            let content = export(&queue(), format);
+            let file_name = format!("queue.{}", format.extension());
+            assert_eq!(
+                import(&file_name, &content).unwrap(),
+                expected,
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn m3u_keeps_titles_and_lengths() {
+        let content = export(&queue(), ExportFormat::M3u);
+        assert!(content.starts_with("#EXTM3U\n#EXTINF:200,First\nhttps://youtu.be/first\n"));
+        assert!(content.contains("#EXTINF:-1,Second, with a comma\n"));
+    }
+
+    #[test]
+    fn json_keeps_when_tracks_were_enqueued() {
+        let queue = queue();
+        let content = export(&queue, ExportFormat::Json);
+        let tracks: Vec<serde_json::Value> = serde_json::from_str(&content).unwrap();
+        assert_eq!(
+            tracks[0]["enqueued_at"],
+            serde_json::to_value(queue[0].enqueued_at).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_is_recognised_by_its_content() {
+        let content = export(&queue(), ExportFormat::Json);
+        assert_eq!(import("queue.txt", &content).unwrap().len(), 3);
+        assert!(matches!(
+            import("queue.json", "not json"),
+            Err(ImportError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_skipped() {
+        let content = "#EXTM3U\n\n  first song  \n# a comment\n\t\n#EXTINF:10,x\nsecond\n";
+        assert_eq!(
+            import("songs.txt", content).unwrap(),
+            vec!["first song", "second"]
+        );
+        assert!(matches!(
+            import("songs.txt", "#EXTM3U\n\n# nothing\n"),
+            Err(ImportError::Empty)
+        ));
+        assert!(matches!(
+            import("queue.json", "[]"),
+            Err(ImportError::Empty)
+        ));
+    }
+
+    #[test]
+    fn long_files_are_cut() {
+        let content = (0..MAX_SONGS + 10)
+            .map(|n| format!("song {}\n", n))
+            .collect::<String>();
+        let queries = import("songs.txt", &content).unwrap();
+        assert_eq!(queries.len(), MAX_SONGS);
+        assert_eq!(queries.last().unwrap(), &format!("song {}", MAX_SONGS - 1));
+    }
+}
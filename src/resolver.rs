@@ -0,0 +1,560 @@
+mod http;
+mod local;
+mod spotify;
+mod youtube;
+
+use std::{fmt, sync::Arc};
+
+use serenity::async_trait;
+use songbird::input::{AuxMetadata, Input};
+
+use crate::{
+    general::{PlaylistPage, PlaylistRange},
+    queue::SourceKind,
+    spotify::SpotifyError,
+};
+
+pub use self::{
+    http::HttpResolver,
+    local::LocalFileResolver,
+    spotify::SpotifyResolver,
+    youtube::{YoutubeBackend, YoutubePlaylistResolver, YoutubeResolver, YtDlp},
+};
+
+/// A track that is ready to be queued.
+pub struct LoadedTrack {
+    pub input: Input,
+    pub metadata: AuxMetadata,
+}
+
+/// Finds the playable source of a track, doing the slow lookups a resolver put off.
+#[async_trait]
+pub trait TrackLoader: Send + Sync {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError>;
+}
+
+/// A track found by a resolver, not playable yet.
+pub struct PendingTrack {
+    pub kind: SourceKind,
+    /// What is known before loading, shown in the queue until the track is loaded.
+    pub metadata: AuxMetadata,
+    pub loader: Arc<dyn TrackLoader>,
+}
+
+pub enum Resolution {
+    Track(Box<PendingTrack>),
+    Playlist(PlaylistPage<PendingTrack>),
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    Spotify(SpotifyError),
+    NotFound(String),
+    Source(String),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Spotify(e) => e.fmt(f),
+            ResolveError::NotFound(query) => write!(f, "Nothing found for `{}`.", query),
+            ResolveError::Source(e) => write!(f, "Could not load the track ({}).", e),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<SpotifyError> for ResolveError {
+    fn from(e: SpotifyError) -> Self {
+        ResolveError::Spotify(e)
+    }
+}
+
+/// Turns what was given to `/play` into tracks of one provider.
+#[async_trait]
+pub trait SourceResolver: Send + Sync {
+    /// Resolvers with a lower priority are asked first.
+    fn priority(&self) -> u8;
+
+    /// Whether `query` is for this resolver, without any network call.
+    fn handles(&self, query: &str) -> bool;
+
+    async fn resolve(&self, query: &str, range: PlaylistRange) -> Result<Resolution, ResolveError>;
+}
+
+/// The registered resolvers, in priority order.
+#[derive(Default)]
+pub struct Resolvers {
+    resolvers: Vec<Box<dyn SourceResolver>>,
+}
+
+impl Resolvers {
+    pub fn new() -> Self {
+        Resolvers::default()
+    }
+
+    pub fn register(mut self, resolver: impl SourceResolver + 'static) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self.resolvers.sort_by_key(|resolver| resolver.priority());
+        self
+    }
+
+    /// Resolves `query` with the first resolver handling it.
+    pub async fn resolve(
+        &self,
+        query: &str,
+        range: PlaylistRange,
+    ) -> Result<Resolution, ResolveError> {
+        let resolver = self
+            .resolvers
+            .iter()
+            .find(|resolver| resolver.handles(query))
+            .ok_or_else(|| ResolveError::NotFound(query.to_string()))?;
+
+        resolver.resolve(query, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use songbird::input::{AudioStreamError, File};
+    use uuid::Uuid;
+
+    use super::{spotify::SpotifyCatalog, *};
+    use crate::{match_cache::MatchCache, spotify::SpotifyTrack};
+
+    fn video(url: &str, title: &str, secs: u64) -> AuxMetadata {
+        AuxMetadata {
+            title: Some(title.to_string()),
+            source_url: (!url.is_empty()).then(|| url.to_string()),
+            duration: Some(Duration::from_secs(secs)),
+            ..AuxMetadata::default()
+        }
+    }
+
+    /// Answers every search and playlist with `videos`, lookups with a made up video.
+    struct FakeYoutube {
+        videos: Vec<AuxMetadata>,
+    }
+
+    #[async_trait]
+    impl YoutubeBackend for FakeYoutube {
+        async fn lookup(&self, url: &str) -> Result<AuxMetadata, AudioStreamError> {
+            Ok(video(url, "Looked up", 200))
+        }
+
+        async fn search(
+            &self,
+            _query: &str,
+            count: usize,
+        ) -> Result<Vec<AuxMetadata>, AudioStreamError> {
+            Ok(self.videos.iter().take(count).cloned().collect())
+        }
+
+        async fn playlist(
+            &self,
+            _url: &str,
+            range: PlaylistRange,
+        ) -> Result<PlaylistPage<AuxMetadata>, AudioStreamError> {
+            Ok(PlaylistPage {
+                tracks: self
+                    .videos
+                    .iter()
+                    .skip(range.offset)
+                    .take(range.limit)
+                    .cloned()
+                    .collect(),
+                total: self.videos.len(),
+            })
+        }
+
+        fn input(&self, url: &str) -> Input {
+            File::new(PathBuf::from(url)).into()
+        }
+    }
+
+    /// A catalog where every album, playlist, artist and show holds `tracks`.
+    struct FakeCatalog {
+        tracks: Vec<SpotifyTrack>,
+    }
+
+    impl FakeCatalog {
+        fn page(&self, range: PlaylistRange) -> PlaylistPage<SpotifyTrack> {
+            PlaylistPage {
+                tracks: self
+                    .tracks
+                    .iter()
+                    .skip(range.offset)
+                    .take(range.limit)
+                    .cloned()
+                    .collect(),
+                total: self.tracks.len(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SpotifyCatalog for FakeCatalog {
+        async fn track(&self, id: &str) -> Result<SpotifyTrack, SpotifyError> {
+            self.tracks
+                .iter()
+                .find(|track| track.id.as_deref() == Some(id))
+                .cloned()
+                .ok_or(SpotifyError::NotFound)
+        }
+
+        async fn episode(&self, id: &str) -> Result<SpotifyTrack, SpotifyError> {
+            self.track(id).await
+        }
+
+        async fn album(
+            &self,
+            _id: &str,
+            range: PlaylistRange,
+        ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+            Ok(self.page(range))
+        }
+
+        async fn playlist(
+            &self,
+            _id: &str,
+            range: PlaylistRange,
+        ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+            Ok(self.page(range))
+        }
+
+        async fn artist(
+            &self,
+            _id: &str,
+            range: PlaylistRange,
+        ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+            Ok(self.page(range))
+        }
+
+        async fn show(
+            &self,
+            _id: &str,
+            range: PlaylistRange,
+        ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+            Ok(self.page(range))
+        }
+    }
+
+    fn spotify_track(id: &str, name: &str) -> SpotifyTrack {
+        SpotifyTrack {
+            id: Some(id.to_string()),
+            name: name.to_string(),
+            artists: vec![String::from("Artist")],
+            duration: Duration::from_secs(200),
+            isrc: None,
+        }
+    }
+
+    /// A folder of its own under the system temp folder.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", name, Uuid::new_v4()))
+    }
+
+    fn range(offset: usize, limit: usize) -> PlaylistRange {
+        PlaylistRange {
+            offset,
+            limit,
+            discography: false,
+        }
+    }
+
+    fn youtube() -> Arc<FakeYoutube> {
+        Arc::new(FakeYoutube {
+            videos: vec![
+                video("https://youtu.be/a", "Artist - Song", 200),
+                video("", "Private video", 0),
+                video("https://youtu.be/b", "Artist - Other Song", 180),
+            ],
+        })
+    }
+
+    /// The resolvers the bot registers, on fakes.
+    fn resolvers(music: PathBuf) -> Resolvers {
+        let youtube = youtube();
+        let catalog = Arc::new(FakeCatalog {
+            tracks: vec![spotify_track("one", "Song")],
+        });
+        let cache = Arc::new(MatchCache::load(temp_path("matches")));
+
+        Resolvers::new()
+            .register(YoutubeResolver::new(youtube.clone()))
+            .register(LocalFileResolver::new(music))
+            .register(HttpResolver::new(reqwest::Client::new()))
+            .register(SpotifyResolver::new(catalog, youtube.clone(), cache))
+            .register(YoutubePlaylistResolver::new(youtube))
+    }
+
+    fn track(resolution: Result<Resolution, ResolveError>) -> PendingTrack {
+        match resolution {
+            Ok(Resolution::Track(track)) => *track,
+            Ok(Resolution::Playlist(_)) => panic!("expected a track, got a playlist"),
+            Err(e) => panic!("expected a track, got {}", e),
+        }
+    }
+
+    fn playlist(resolution: Result<Resolution, ResolveError>) -> PlaylistPage<PendingTrack> {
+        match resolution {
+            Ok(Resolution::Playlist(page)) => page,
+            Ok(Resolution::Track(_)) => panic!("expected a playlist, got a track"),
+            Err(e) => panic!("expected a playlist, got {}", e),
+        }
+    }
+
+    fn title(track: &PendingTrack) -> &str {
+        track.metadata.title.as_deref().unwrap_or_default()
+    }
+
+    struct Named {
+        name: &'static str,
+        priority: u8,
+        prefix: &'static str,
+    }
+
+    #[async_trait]
+    impl SourceResolver for Named {
+        fn priority(&self) -> u8 {
+            self.priority
+        }
+
+        fn handles(&self, query: &str) -> bool {
+            query.starts_with(self.prefix)
+        }
+
+        async fn resolve(
+            &self,
+            _query: &str,
+            _range: PlaylistRange,
+        ) -> Result<Resolution, ResolveError> {
+            Ok(Resolution::Playlist(PlaylistPage {
+                tracks: Vec::new(),
+                total: self.name.len(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn lower_priority_is_asked_first() {
+        let resolvers = Resolvers::new()
+            .register(Named {
+                name: "any",
+                priority: 50,
+                prefix: "",
+            })
+            .register(Named {
+                name: "links",
+                priority: 10,
+                prefix: "http",
+            });
+
+        let everything = range(0, usize::MAX);
+        let link = playlist(resolvers.resolve("https://x", everything).await);
+        let text = playlist(resolvers.resolve("some text", everything).await);
+        assert_eq!(link.total, "links".len());
+        assert_eq!(text.total, "any".len());
+    }
+
+    #[tokio::test]
+    async fn unclaimed_queries_are_not_found() {
+        let resolvers = Resolvers::new().register(Named {
+            name: "links",
+            priority: 10,
+            prefix: "http",
+        });
+
+        let result = resolvers.resolve("some text", range(0, 10)).await;
+        assert!(matches!(result, Err(ResolveError::NotFound(query)) if query == "some text"));
+    }
+
+    #[tokio::test]
+    async fn queries_go_to_their_resolver() {
+        let resolvers = resolvers(temp_path("music"));
+        let everything = range(0, usize::MAX);
+
+        let cases = [
+            ("never gonna give you up", SourceKind::YouTube),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                SourceKind::YouTube,
+            ),
+            ("https://open.spotify.com/track/one", SourceKind::Spotify),
+            ("spotify:track:one", SourceKind::Spotify),
+            ("https://example.com/song.mp3?token=1", SourceKind::Http),
+            ("https://example.com/page.html", SourceKind::YouTube),
+        ];
+        for (query, kind) in cases {
+            let track = track(resolvers.resolve(query, everything).await);
+            assert_eq!(track.kind, kind, "{}", query);
+        }
+
+        let page = playlist(
+            resolvers
+                .resolve("https://www.youtube.com/playlist?list=PL1", everything)
+                .await,
+        );
+        assert!(page
+            .tracks
+            .iter()
+            .all(|track| track.kind == SourceKind::YouTube));
+        let page = playlist(
+            resolvers
+                .resolve("https://open.spotify.com/album/abc", everything)
+                .await,
+        );
+        assert!(page
+            .tracks
+            .iter()
+            .all(|track| track.kind == SourceKind::Spotify));
+    }
+
+    #[tokio::test]
+    async fn youtube_searches_and_links_become_tracks() {
+        let resolver = YoutubeResolver::new(youtube());
+
+        let search = track(resolver.resolve("artist song", range(0, 1)).await);
+        assert_eq!(title(&search), "artist song");
+        let loaded = search.loader.load().await.unwrap();
+        assert_eq!(
+            loaded.metadata.source_url.as_deref(),
+            Some("https://youtu.be/a")
+        );
+
+        let link = track(resolver.resolve("https://youtu.be/z", range(0, 1)).await);
+        let loaded = link.loader.load().await.unwrap();
+        assert_eq!(loaded.metadata.title.as_deref(), Some("Looked up"));
+        assert_eq!(
+            loaded.metadata.source_url.as_deref(),
+            Some("https://youtu.be/z")
+        );
+    }
+
+    #[tokio::test]
+    async fn youtube_playlists_skip_videos_without_url() {
+        let resolver = YoutubePlaylistResolver::new(youtube());
+
+        let page = playlist(
+            resolver
+                .resolve("https://www.youtube.com/playlist?list=PL1", range(0, 10))
+                .await,
+        );
+        let titles: Vec<_> = page.tracks.iter().map(title).collect();
+        assert_eq!(titles, ["Artist - Song", "Artist - Other Song"]);
+        assert_eq!(page.total, 3);
+    }
+
+    #[tokio::test]
+    async fn spotify_tracks_are_matched_on_youtube() {
+        let cache = Arc::new(MatchCache::load(temp_path("matches")));
+        let resolver = SpotifyResolver::new(
+            Arc::new(FakeCatalog {
+                tracks: vec![spotify_track("one", "Song")],
+            }),
+            youtube(),
+            cache.clone(),
+        );
+
+        let pending = track(resolver.resolve("spotify:track:one", range(0, 1)).await);
+        assert_eq!(title(&pending), "Artist - Song");
+        assert_eq!(pending.metadata.duration, Some(Duration::from_secs(200)));
+
+        let loaded = pending.loader.load().await.unwrap();
+        assert_eq!(
+            loaded.metadata.source_url.as_deref(),
+            Some("https://youtu.be/a")
+        );
+        // Known from now on, without asking the catalog or YouTube.
+        let cached = cache.get("one").unwrap();
+        assert_eq!(cached.source_url.as_deref(), Some("https://youtu.be/a"));
+        let again = track(resolver.resolve("spotify:track:one", range(0, 1)).await);
+        assert_eq!(title(&again), "Artist - Song");
+
+        let missing = resolver.resolve("spotify:track:two", range(0, 1)).await;
+        assert!(matches!(
+            missing,
+            Err(ResolveError::Spotify(SpotifyError::NotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn spotify_collections_become_playlists() {
+        let resolver = SpotifyResolver::new(
+            Arc::new(FakeCatalog {
+                tracks: vec![
+                    spotify_track("one", "First"),
+                    spotify_track("two", "Second"),
+                    spotify_track("three", "Third"),
+                ],
+            }),
+            youtube(),
+            Arc::new(MatchCache::load(temp_path("matches"))),
+        );
+
+        for link in [
+            "https://open.spotify.com/album/abc",
+            "https://open.spotify.com/playlist/abc",
+            "https://open.spotify.com/artist/abc",
+            "https://open.spotify.com/show/abc",
+        ] {
+            let page = playlist(resolver.resolve(link, range(1, 1)).await);
+            let titles: Vec<_> = page.tracks.iter().map(title).collect();
+            assert_eq!(titles, ["Artist - Second"], "{}", link);
+            assert_eq!(page.total, 3, "{}", link);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_links_to_audio_files() {
+        let resolver = HttpResolver::new(reqwest::Client::new());
+
+        assert!(resolver.handles("https://example.com/a/song.MP3"));
+        assert!(resolver.handles("http://example.com/song.ogg?download=1"));
+        assert!(!resolver.handles("https://example.com/song"));
+        assert!(!resolver.handles("https://example.com/mp3"));
+        assert!(!resolver.handles("ftp://example.com/song.mp3"));
+
+        let pending = track(
+            resolver
+                .resolve("https://example.com/a/song.mp3?download=1", range(0, 1))
+                .await,
+        );
+        assert_eq!(title(&pending), "song.mp3");
+    }
+
+    #[tokio::test]
+    async fn local_files_and_folders() {
+        let music = temp_path("music");
+        fs::create_dir_all(music.join("album")).unwrap();
+        for name in ["b.mp3", "a.mp3", "c.mp3"] {
+            fs::write(music.join("album").join(name), b"").unwrap();
+        }
+        fs::write(music.join("single.mp3"), b"").unwrap();
+        let resolver = LocalFileResolver::new(&music);
+
+        let single = track(resolver.resolve("file:single.mp3", range(0, 1)).await);
+        assert_eq!(title(&single), "single");
+        assert_eq!(single.kind, SourceKind::LocalFile);
+
+        let album = playlist(resolver.resolve("file: album", range(1, 5)).await);
+        let titles: Vec<_> = album.tracks.iter().map(title).collect();
+        assert_eq!(titles, ["b", "c"]);
+        assert_eq!(album.total, 3);
+
+        for query in ["file:../etc/passwd", "file:/etc/passwd", "file:missing"] {
+            let result = resolver.resolve(query, range(0, 1)).await;
+            assert!(
+                matches!(result, Err(ResolveError::NotFound(_))),
+                "{}",
+                query
+            );
+        }
+
+        fs::remove_dir_all(music).unwrap();
+    }
+}
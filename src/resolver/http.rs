@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use serenity::async_trait;
+use songbird::input::{AuxMetadata, HttpRequest};
+
+use super::{LoadedTrack, PendingTrack, Resolution, ResolveError, SourceResolver, TrackLoader};
+use crate::{general::PlaylistRange, queue::SourceKind};
+
+const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "m4a", "aac", "flac", "wav", "ogg", "opus"];
+
+/// Links straight to an audio file, streamed without yt-dlp.
+pub struct HttpResolver {
+    http_client: reqwest::Client,
+}
+
+impl HttpResolver {
+    pub fn new(http_client: reqwest::Client) -> Self {
+        HttpResolver { http_client }
+    }
+}
+
+#[async_trait]
+impl SourceResolver for HttpResolver {
+    fn priority(&self) -> u8 {
+        30
+    }
+
+    fn handles(&self, query: &str) -> bool {
+        if !query.starts_with("http://") && !query.starts_with("https://") {
+            return false;
+        }
+
+        let path = query.split(['?', '#']).next().unwrap_or_default();
+        path.rsplit_once('.').is_some_and(|(_, extension)| {
+            AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+        })
+    }
+
+    async fn resolve(
+        &self,
+        query: &str,
+        _range: PlaylistRange,
+    ) -> Result<Resolution, ResolveError> {
+        let loader = HttpLoader {
+            http_client: self.http_client.clone(),
+            url: query.to_string(),
+        };
+
+        Ok(Resolution::Track(Box::new(PendingTrack {
+            kind: SourceKind::Http,
+            metadata: loader.metadata(),
+            loader: Arc::new(loader),
+        })))
+    }
+}
+
+struct HttpLoader {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+impl HttpLoader {
+    /// The file name is the best title we have.
+    fn metadata(&self) -> AuxMetadata {
+        let path = self.url.split(['?', '#']).next().unwrap_or_default();
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+
+        AuxMetadata {
+            title: Some(file_name.to_string()),
+            source_url: Some(self.url.clone()),
+            ..AuxMetadata::default()
+        }
+    }
+}
+
+#[async_trait]
+impl TrackLoader for HttpLoader {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError> {
+        Ok(LoadedTrack {
+            input: HttpRequest::new(self.http_client.clone(), self.url.clone()).into(),
+            metadata: self.metadata(),
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use serenity::async_trait;
+use songbird::input::{AuxMetadata, File};
+
+use super::{LoadedTrack, PendingTrack, Resolution, ResolveError, SourceResolver, TrackLoader};
+use crate::{
+    general::{PlaylistPage, PlaylistRange},
+    queue::SourceKind,
+};
+
+const PREFIX: &str = "file:";
+
+/// `file:<name>` plays a file of the music folder, `file:<folder>` every file in it.
+pub struct LocalFileResolver {
+    root: PathBuf,
+}
+
+impl LocalFileResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalFileResolver { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl SourceResolver for LocalFileResolver {
+    fn priority(&self) -> u8 {
+        40
+    }
+
+    fn handles(&self, query: &str) -> bool {
+        query.starts_with(PREFIX)
+    }
+
+    async fn resolve(&self, query: &str, range: PlaylistRange) -> Result<Resolution, ResolveError> {
+        let name = query.trim_start_matches(PREFIX).trim();
+
+        // Only plain names inside the music folder, no way out of it.
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(ResolveError::NotFound(name.to_string()));
+        }
+        let path = self.root.join(relative);
+
+        if path.is_file() {
+            return Ok(Resolution::Track(Box::new(local_track(path))));
+        }
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&path)
+            .map_err(|_| ResolveError::NotFound(name.to_string()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+
+        Ok(Resolution::Playlist(PlaylistPage {
+            total: files.len(),
+            tracks: files
+                .into_iter()
+                .skip(range.offset)
+                .take(range.limit)
+                .map(local_track)
+                .collect(),
+        }))
+    }
+}
+
+fn local_track(path: PathBuf) -> PendingTrack {
+    let loader = FileLoader { path };
+    PendingTrack {
+        kind: SourceKind::LocalFile,
+        metadata: loader.metadata(),
+        loader: Arc::new(loader),
+    }
+}
+
+struct FileLoader {
+    path: PathBuf,
+}
+
+impl FileLoader {
+    fn metadata(&self) -> AuxMetadata {
+        AuxMetadata {
+            title: self
+                .path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned()),
+            ..AuxMetadata::default()
+        }
+    }
+}
+
+#[async_trait]
+impl TrackLoader for FileLoader {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError> {
+        Ok(LoadedTrack {
+            input: File::new(self.path.clone()).into(),
+            metadata: self.metadata(),
+        })
+    }
+}
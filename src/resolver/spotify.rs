@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use serenity::async_trait;
+use songbird::input::AuxMetadata;
+
+use super::{
+    youtube::load_video, LoadedTrack, PendingTrack, Resolution, ResolveError, SourceResolver,
+    TrackLoader, YoutubeBackend,
+};
+use crate::{
+    general::{PlaylistPage, PlaylistRange},
+    match_cache::MatchCache,
+    queue::SourceKind,
+    spotify::{self, SpotifyClient, SpotifyError, SpotifyLink, SpotifyTrack},
+};
+
+/// The Spotify lookups the resolver needs, so it can be run against a fake catalog.
+#[async_trait]
+pub trait SpotifyCatalog: Send + Sync {
+    async fn track(&self, id: &str) -> Result<SpotifyTrack, SpotifyError>;
+
+    async fn episode(&self, id: &str) -> Result<SpotifyTrack, SpotifyError>;
+
+    async fn album(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError>;
+
+    async fn playlist(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError>;
+
+    async fn artist(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError>;
+
+    async fn show(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError>;
+}
+
+#[async_trait]
+impl SpotifyCatalog for SpotifyClient {
+    async fn track(&self, id: &str) -> Result<SpotifyTrack, SpotifyError> {
+        spotify::get_spoti_track(id, self).await
+    }
+
+    async fn episode(&self, id: &str) -> Result<SpotifyTrack, SpotifyError> {
+        spotify::get_spoti_episode(id, self).await
+    }
+
+    async fn album(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+        spotify::get_urls_album(id.to_string(), range, self).await
+    }
+
+    async fn playlist(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+        spotify::get_urls_playlist(id.to_string(), range, self).await
+    }
+
+    async fn artist(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+        spotify::get_urls_artist(id.to_string(), range, self).await
+    }
+
+    async fn show(
+        &self,
+        id: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+        spotify::get_urls_show(id.to_string(), range, self).await
+    }
+}
+
+/// Spotify links, played through their best YouTube match.
+pub struct SpotifyResolver {
+    catalog: Arc<dyn SpotifyCatalog>,
+    youtube: Arc<dyn YoutubeBackend>,
+    cache: Arc<MatchCache>,
+}
+
+impl SpotifyResolver {
+    pub fn new(
+        catalog: Arc<dyn SpotifyCatalog>,
+        youtube: Arc<dyn YoutubeBackend>,
+        cache: Arc<MatchCache>,
+    ) -> Self {
+        SpotifyResolver {
+            catalog,
+            youtube,
+            cache,
+        }
+    }
+
+    fn matched_track(&self, track: SpotifyTrack) -> PendingTrack {
+        PendingTrack {
+            kind: SourceKind::Spotify,
+            metadata: AuxMetadata {
+                title: Some(track.display_title()),
+                duration: Some(track.duration),
+                ..AuxMetadata::default()
+            },
+            loader: Arc::new(MatchLoader {
+                track,
+                youtube: self.youtube.clone(),
+                cache: self.cache.clone(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl SourceResolver for SpotifyResolver {
+    fn priority(&self) -> u8 {
+        20
+    }
+
+    fn handles(&self, query: &str) -> bool {
+        spotify::parse_link(query).is_some()
+    }
+
+    async fn resolve(&self, query: &str, range: PlaylistRange) -> Result<Resolution, ResolveError> {
+        let link =
+            spotify::parse_link(query).ok_or_else(|| SpotifyError::InvalidId(query.to_string()))?;
+
+        let page = match link {
+            SpotifyLink::Track(id) | SpotifyLink::Episode(id) => {
+                // A known track needs neither Spotify nor a YouTube search.
+                if let Some(metadata) = self.cache.get(id) {
+                    return Ok(Resolution::Track(Box::new(PendingTrack {
+                        kind: SourceKind::Spotify,
+                        metadata: metadata.clone(),
+                        loader: Arc::new(CachedLoader {
+                            metadata,
+                            youtube: self.youtube.clone(),
+                        }),
+                    })));
+                }
+
+                let track = match link {
+                    SpotifyLink::Episode(_) => self.catalog.episode(id).await?,
+                    _ => self.catalog.track(id).await?,
+                };
+                return Ok(Resolution::Track(Box::new(self.matched_track(track))));
+            }
+            SpotifyLink::Album(id) => self.catalog.album(id, range).await?,
+            SpotifyLink::Playlist(id) => self.catalog.playlist(id, range).await?,
+            SpotifyLink::Artist(id) => self.catalog.artist(id, range).await?,
+            SpotifyLink::Show(id) => self.catalog.show(id, range).await?,
+        };
+
+        Ok(Resolution::Playlist(PlaylistPage {
+            tracks: page
+                .tracks
+                .into_iter()
+                .map(|track| self.matched_track(track))
+                .collect(),
+            total: page.total,
+        }))
+    }
+}
+
+struct CachedLoader {
+    metadata: AuxMetadata,
+    youtube: Arc<dyn YoutubeBackend>,
+}
+
+#[async_trait]
+impl TrackLoader for CachedLoader {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError> {
+        load_video(&self.youtube, self.metadata.clone())
+    }
+}
+
+/// Searches YouTube for the track, unless its match is cached.
+struct MatchLoader {
+    track: SpotifyTrack,
+    youtube: Arc<dyn YoutubeBackend>,
+    cache: Arc<MatchCache>,
+}
+
+#[async_trait]
+impl TrackLoader for MatchLoader {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError> {
+        let id = self.track.id.as_deref();
+
+        let metadata = match id.and_then(|id| self.cache.get(id)) {
+            Some(metadata) => metadata,
+            None => {
+                let metadata = spotify::find_youtube_match(&self.track, self.youtube.as_ref())
+                    .await
+                    .map_err(|e| ResolveError::Source(e.to_string()))?;
+                if let Some(id) = id {
+                    self.cache.insert(id, &metadata);
+                    self.cache.flush();
+                }
+                metadata
+            }
+        };
+
+        load_video(&self.youtube, metadata)
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    process::{Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde_json::Value;
+use serenity::async_trait;
+use songbird::input::{AudioStreamError, AuxMetadata, Compose, Input, YoutubeDl};
+
+use super::{LoadedTrack, PendingTrack, Resolution, ResolveError, SourceResolver, TrackLoader};
+use crate::{
+    general::{PlaylistPage, PlaylistRange},
+    queue::SourceKind,
+};
+
+/// What the YouTube resolvers need from yt-dlp, so they can be run against a fake.
+#[async_trait]
+pub trait YoutubeBackend: Send + Sync {
+    /// Metadata of a single video.
+    async fn lookup(&self, url: &str) -> Result<AuxMetadata, AudioStreamError>;
+
+    async fn search(&self, query: &str, count: usize)
+        -> Result<Vec<AuxMetadata>, AudioStreamError>;
+
+    /// Videos of a playlist, with whatever metadata the listing has.
+    async fn playlist(
+        &self,
+        url: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<AuxMetadata>, AudioStreamError>;
+
+    /// Stream of a video, fetched when it starts playing.
+    fn input(&self, url: &str) -> Input;
+}
+
+pub struct YtDlp {
+    http_client: reqwest::Client,
+}
+
+impl YtDlp {
+    pub fn new(http_client: reqwest::Client) -> Self {
+        YtDlp { http_client }
+    }
+}
+
+#[async_trait]
+impl YoutubeBackend for YtDlp {
+    async fn lookup(&self, url: &str) -> Result<AuxMetadata, AudioStreamError> {
+        YoutubeDl::new(self.http_client.clone(), url.to_string())
+            .aux_metadata()
+            .await
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        count: usize,
+    ) -> Result<Vec<AuxMetadata>, AudioStreamError> {
+        YoutubeDl::new_search(self.http_client.clone(), query.to_string())
+            .search(Some(count))
+            .await
+    }
+
+    async fn playlist(
+        &self,
+        url: &str,
+        range: PlaylistRange,
+    ) -> Result<PlaylistPage<AuxMetadata>, AudioStreamError> {
+        let url = url.to_string();
+        tokio::task::spawn_blocking(move || list_playlist(&url, range))
+            .await
+            .map_err(|e| AudioStreamError::Fail(Box::new(e)))?
+    }
+
+    fn input(&self, url: &str) -> Input {
+        YoutubeDl::new(self.http_client.clone(), url.to_string()).into()
+    }
+}
+
+fn list_playlist(
+    url: &str,
+    range: PlaylistRange,
+) -> Result<PlaylistPage<AuxMetadata>, AudioStreamError> {
+    let mut result = Vec::<AuxMetadata>::new();
+
+    // No end is given, every entry is listed so the total is known.
+    let output = Command::new("yt-dlp")
+        .arg("-j")
+        .arg("--flat-playlist")
+        .arg("--playlist-start")
+        .arg((range.offset + 1).to_string())
+        .arg(url)
+        .stdout(Stdio::piped())
+        .output()
+        .map_err(|e| AudioStreamError::Fail(Box::new(e)))?;
+
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let mut total = range.offset;
+
+    for line in output_str.lines() {
+        total += 1;
+        if result.len() >= range.limit {
+            continue;
+        }
+
+        let video_info: Value = match serde_json::from_str(line) {
+            Ok(video_info) => video_info,
+            Err(e) => {
+                eprintln!("Skipping malformed playlist entry: {}", e);
+                continue;
+            }
+        };
+
+        // Extract the 'url' field from the JSON object
+        if let Some(url) = video_info.get("url").and_then(|u| u.as_str()) {
+            result.push(AuxMetadata {
+                title: video_info["title"].as_str().map(str::to_string),
+                source_url: Some(url.to_string()),
+                duration: video_info["duration"].as_f64().map(Duration::from_secs_f64),
+                ..AuxMetadata::default()
+            });
+        } else {
+            eprintln!("No URL found in video entry");
+        }
+    }
+
+    Ok(PlaylistPage {
+        tracks: result,
+        total,
+    })
+}
+
+/// YouTube links and plain text searches, the fallback for anything not claimed by another resolver.
+pub struct YoutubeResolver {
+    backend: Arc<dyn YoutubeBackend>,
+}
+
+impl YoutubeResolver {
+    pub fn new(backend: Arc<dyn YoutubeBackend>) -> Self {
+        YoutubeResolver { backend }
+    }
+}
+
+#[async_trait]
+impl SourceResolver for YoutubeResolver {
+    fn priority(&self) -> u8 {
+        u8::MAX
+    }
+
+    fn handles(&self, _query: &str) -> bool {
+        true
+    }
+
+    async fn resolve(
+        &self,
+        query: &str,
+        _range: PlaylistRange,
+    ) -> Result<Resolution, ResolveError> {
+        let metadata = AuxMetadata {
+            title: Some(query.to_string()),
+            ..AuxMetadata::default()
+        };
+        let query = if query.starts_with("http") {
+            YoutubeQuery::Url(query.to_string())
+        } else {
+            YoutubeQuery::Search(query.to_string())
+        };
+        Ok(Resolution::Track(Box::new(youtube_track(
+            &self.backend,
+            query,
+            metadata,
+        ))))
+    }
+}
+
+/// YouTube playlists, every video is looked up on its own when loaded.
+pub struct YoutubePlaylistResolver {
+    backend: Arc<dyn YoutubeBackend>,
+}
+
+impl YoutubePlaylistResolver {
+    pub fn new(backend: Arc<dyn YoutubeBackend>) -> Self {
+        YoutubePlaylistResolver { backend }
+    }
+}
+
+#[async_trait]
+impl SourceResolver for YoutubePlaylistResolver {
+    fn priority(&self) -> u8 {
+        10
+    }
+
+    fn handles(&self, query: &str) -> bool {
+        query.starts_with("http") && query.contains("list=")
+    }
+
+    async fn resolve(&self, query: &str, range: PlaylistRange) -> Result<Resolution, ResolveError> {
+        let page = self
+            .backend
+            .playlist(query, range)
+            .await
+            .map_err(|e| ResolveError::Source(e.to_string()))?;
+
+        Ok(Resolution::Playlist(PlaylistPage {
+            tracks: page
+                .tracks
+                .into_iter()
+                .filter_map(|metadata| {
+                    let url = metadata.source_url.clone()?;
+                    Some(youtube_track(
+                        &self.backend,
+                        YoutubeQuery::Url(url),
+                        metadata,
+                    ))
+                })
+                .collect(),
+            total: page.total,
+        }))
+    }
+}
+
+enum YoutubeQuery {
+    Url(String),
+    Search(String),
+}
+
+struct YoutubeLoader {
+    backend: Arc<dyn YoutubeBackend>,
+    query: YoutubeQuery,
+}
+
+fn youtube_track(
+    backend: &Arc<dyn YoutubeBackend>,
+    query: YoutubeQuery,
+    metadata: AuxMetadata,
+) -> PendingTrack {
+    PendingTrack {
+        kind: SourceKind::YouTube,
+        metadata,
+        loader: Arc::new(YoutubeLoader {
+            backend: backend.clone(),
+            query,
+        }),
+    }
+}
+
+#[async_trait]
+impl TrackLoader for YoutubeLoader {
+    async fn load(&self) -> Result<LoadedTrack, ResolveError> {
+        let metadata = match &self.query {
+            YoutubeQuery::Url(url) => self.backend.lookup(url).await,
+            YoutubeQuery::Search(query) => {
+                self.backend.search(query, 1).await.and_then(|results| {
+                    results.into_iter().next().ok_or_else(|| {
+                        AudioStreamError::Fail(format!("no results for '{}'", query).into())
+                    })
+                })
+            }
+        }
+        .map_err(|e| ResolveError::Source(e.to_string()))?;
+
+        load_video(&self.backend, metadata)
+    }
+}
+
+/// Pairs already known video metadata with its stream.
+pub(super) fn load_video(
+    backend: &Arc<dyn YoutubeBackend>,
+    metadata: AuxMetadata,
+) -> Result<LoadedTrack, ResolveError> {
+    let url = metadata
+        .source_url
+        .clone()
+        .ok_or_else(|| ResolveError::Source(String::from("video without an URL")))?;
+
+    Ok(LoadedTrack {
+        input: backend.input(&url),
+        metadata,
+    })
+}
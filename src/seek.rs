@@ -0,0 +1,198 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::queue::format_length;
+
+/// Where `/seek` should move the current track to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekTarget {
+    /// `1:23`, `1:02:03`, `1h2m` or plain seconds.
+    To(Duration),
+    /// `+30`
+    Forward(Duration),
+    /// `-15`
+    Backward(Duration),
+    /// `50%` of the track length.
+    Percent(f64),
+}
+
+#[derive(Debug)]
+pub enum SeekError {
+    Invalid(String),
+    NothingPlaying,
+    UnknownLength,
+    PastEnd(Duration),
+    Failed(String),
+}
+
+impl fmt::Display for SeekError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeekError::Invalid(input) => write!(
+                f,
+                "`{}` is not a position, try `1:23`, `1h2m`, `90`, `+30`, `-15` or `50%`.",
+                input
+            ),
+            SeekError::NothingPlaying => write!(f, "Nothing is playing."),
+            SeekError::UnknownLength => write!(f, "This track has no known length."),
+            SeekError::PastEnd(length) => {
+                write!(f, "The track is only {} long.", format_length(*length))
+            }
+            SeekError::Failed(e) => write!(f, "Could not seek ({}).", e),
+        }
+    }
+}
+
+impl FromStr for SeekTarget {
+    type Err = SeekError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim();
+        let invalid = || SeekError::Invalid(trimmed.to_string());
+
+        if let Some(percent) = trimmed.strip_suffix('%') {
+            return match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(SeekTarget::Percent(percent)),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some(offset) = trimmed.strip_prefix('+') {
+            return parse_time(offset)
+                .map(SeekTarget::Forward)
+                .ok_or_else(invalid);
+        }
+        if let Some(offset) = trimmed.strip_prefix('-') {
+            return parse_time(offset)
+                .map(SeekTarget::Backward)
+                .ok_or_else(invalid);
+        }
+        parse_time(trimmed).map(SeekTarget::To).ok_or_else(invalid)
+    }
+}
+
+impl SeekTarget {
+    /// The absolute position to seek to, given where the track is and how long it is.
+    pub fn resolve(
+        self,
+        position: Duration,
+        length: Option<Duration>,
+    ) -> Result<Duration, SeekError> {
+        let target = match self {
+            SeekTarget::To(time) => time,
+            SeekTarget::Forward(offset) => position + offset,
+            SeekTarget::Backward(offset) => position.saturating_sub(offset),
+            SeekTarget::Percent(percent) => length
+                .ok_or(SeekError::UnknownLength)?
+                .mul_f64(percent / 100.0),
+        };
+
+        match length {
+            Some(length) if target > length => Err(SeekError::PastEnd(length)),
+            _ => Ok(target),
+        }
+    }
+}
+
+/// `h:mm:ss`, `m:ss`, plain seconds or anything humantime understands.
+fn parse_time(input: &str) -> Option<Duration> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+
+    if input.contains(':') {
+        let parts = input
+            .split(':')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let secs = match parts[..] {
+            [minutes, seconds] if seconds < 60 => minutes * 60 + seconds,
+            [hours, minutes, seconds] if minutes < 60 && seconds < 60 => {
+                hours * 3600 + minutes * 60 + seconds
+            }
+            _ => return None,
+        };
+        return Some(Duration::from_secs(secs));
+    }
+
+    if let Ok(secs) = input.parse::<f64>() {
+        return (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs));
+    }
+
+    humantime::parse_duration(input).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn target(input: &str) -> SeekTarget {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_clock_times() {
+        assert_eq!(target("1:23"), SeekTarget::To(secs(83)));
+        assert_eq!(target("0:05"), SeekTarget::To(secs(5)));
+        assert_eq!(target("1:02:03"), SeekTarget::To(secs(3723)));
+        assert_eq!(target(" 90 "), SeekTarget::To(secs(90)));
+        assert_eq!(target("1h2m"), SeekTarget::To(secs(3720)));
+    }
+
+    #[test]
+    fn parses_offsets_and_percentages() {
+        assert_eq!(target("+30"), SeekTarget::Forward(secs(30)));
+        assert_eq!(target("-10"), SeekTarget::Backward(secs(10)));
+        assert_eq!(target("-1:30"), SeekTarget::Backward(secs(90)));
+        assert_eq!(target("50%"), SeekTarget::Percent(50.0));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for input in [
+            "", "abc", "1:60", "1:02:60", "1:60:00", "1:2:3:4", ":30", "+", "-x", "101%", "-5%",
+            "1.5:00",
+        ] {
+            assert!(
+                matches!(input.parse::<SeekTarget>(), Err(SeekError::Invalid(_))),
+                "{input:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_against_the_position() {
+        let length = Some(secs(200));
+        assert_eq!(target("1:00").resolve(secs(100), length).unwrap(), secs(60));
+        assert_eq!(target("+30").resolve(secs(100), length).unwrap(), secs(130));
+        assert_eq!(target("-10").resolve(secs(100), length).unwrap(), secs(90));
+        assert_eq!(target("25%").resolve(secs(100), length).unwrap(), secs(50));
+    }
+
+    #[test]
+    fn stays_within_the_track() {
+        let length = Some(secs(200));
+        assert_eq!(target("-30").resolve(secs(10), length).unwrap(), secs(0));
+        assert_eq!(target("100%").resolve(secs(10), length).unwrap(), secs(200));
+        assert!(matches!(
+            target("+30").resolve(secs(190), length),
+            Err(SeekError::PastEnd(end)) if end == secs(200)
+        ));
+        assert!(matches!(
+            target("3:21").resolve(secs(0), length),
+            Err(SeekError::PastEnd(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_lengths() {
+        assert_eq!(target("+30").resolve(secs(10), None).unwrap(), secs(40));
+        assert!(matches!(
+            target("50%").resolve(secs(10), None),
+            Err(SeekError::UnknownLength)
+        ));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+
+use crate::{
+    filters::FilterSettings,
+    loudness::DEFAULT_TARGET_LUFS,
+    player::{DuckMode, LoopMode},
+};
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 15 * 60;
+const DEFAULT_PLAYLIST_LIMIT: usize = 200;
+const DEFAULT_VOLUME: u16 = 100;
+const DEFAULT_DUCK_LEVEL: u16 = 30;
+
+/// Per-guild preferences, persisted as JSON so they survive restarts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    pub idle_timeout_secs: u64,
+    /// Most tracks enqueued from a single playlist or album.
+    pub playlist_limit: usize,
+    pub loop_mode: LoopMode,
+    /// Percent, from 0 to 200.
+    pub music_volume: u16,
+    /// Percent, from 0 to 200.
+    pub soundboard_volume: u16,
+    /// What happens to the music while a soundboard clip plays.
+    pub duck_mode: DuckMode,
+    /// Percent of the music volume kept while ducking.
+    pub duck_level: u16,
+    /// Whether music and soundboard clips are brought to `target_lufs`.
+    pub normalization: bool,
+    pub target_lufs: f64,
+    pub filters: FilterSettings,
+    /// Seconds the end of a track overlaps the start of the next one, 0 when off.
+    pub crossfade_secs: u64,
+}
+
+impl GuildSettings {
+    /// The loudness tracks are brought to, `None` while normalization is off.
+    pub fn normalization_target(&self) -> Option<f64> {
+        self.normalization.then_some(self.target_lufs)
+    }
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        GuildSettings {
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            playlist_limit: DEFAULT_PLAYLIST_LIMIT,
+            loop_mode: LoopMode::Off,
+            music_volume: DEFAULT_VOLUME,
+            soundboard_volume: DEFAULT_VOLUME,
+            duck_mode: DuckMode::Duck,
+            duck_level: DEFAULT_DUCK_LEVEL,
+            normalization: false,
+            target_lufs: DEFAULT_TARGET_LUFS,
+            filters: FilterSettings::default(),
+            crossfade_secs: 0,
+        }
+    }
+}
+
+pub struct SettingsStore {
+    writer: JsonWriter<HashMap<GuildId, GuildSettings>>,
+    guilds: Mutex<HashMap<GuildId, GuildSettings>>,
+}
+
+impl SettingsStore {
+    /// Loads the settings file, a missing or unreadable file just means defaults everywhere.
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut guilds: HashMap<GuildId, GuildSettings> = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring malformed settings file {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        for settings in guilds.values_mut() {
+            settings.filters.sanitize();
+        }
+
+        SettingsStore {
+            writer: JsonWriter::new(path),
+            guilds: Mutex::new(guilds),
+        }
+    }
+
+    pub fn get(&self, guild_id: GuildId) -> GuildSettings {
+        self.guilds
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Applies `change` to the guild settings, the file is written back in the background.
+    pub fn update(&self, guild_id: GuildId, change: impl FnOnce(&mut GuildSettings)) {
+        let mut guilds = self.guilds.lock().unwrap();
+        change(guilds.entry(guild_id).or_default());
+        self.writer.save(guilds.clone());
+    }
+}
+
+/// The songbird volume of a percentage, 1.0 is the original level.
+pub fn gain(percent: u16) -> f32 {
+    f32::from(percent) / 100.0
+}
+
+/// Writes `value` as pretty JSON, creating the parent folder if needed.
+///
+/// The JSON goes to a temporary file first, renamed over `path` once complete,
+/// so a crash halfway leaves the previous file as it was.
+pub fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let content = serde_json::to_string_pretty(value)?;
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Writes a JSON file on the blocking thread pool, so async tasks never wait for the disk.
+///
+/// Values saved while a write is going on replace each other, only the latest is written.
+pub struct JsonWriter<T> {
+    path: PathBuf,
+    pending: Arc<Mutex<Pending<T>>>,
+}
+
+struct Pending<T> {
+    value: Option<T>,
+    writing: bool,
+}
+
+impl<T: Serialize + Send + 'static> JsonWriter<T> {
+    pub fn new(path: PathBuf) -> Self {
+        JsonWriter {
+            path,
+            pending: Arc::new(Mutex::new(Pending {
+                value: None,
+                writing: false,
+            })),
+        }
+    }
+
+    pub fn save(&self, value: T) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.value = Some(value);
+        if pending.writing {
+            return;
+        }
+        pending.writing = true;
+
+        let path = self.path.clone();
+        let pending = self.pending.clone();
+        tokio::task::spawn_blocking(move || loop {
+            let value = {
+                let mut pending = pending.lock().unwrap();
+                match pending.value.take() {
+                    Some(value) => value,
+                    None => {
+                        pending.writing = false;
+                        break;
+                    }
+                }
+            };
+            if let Err(e) = save_json(&path, &value) {
+                eprintln!("Failed to save {:?}: {}", path, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, time::Duration};
+
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir()
+            .join(format!("settings-{}", uuid::Uuid::new_v4()))
+            .join("settings.json")
+    }
+
+    #[test]
+    fn save_json_replaces_the_file_whole() {
+        let path = temp_path();
+        save_json(&path, &vec![1, 2, 3]).unwrap();
+        save_json(&path, &vec![4]).unwrap();
+
+        let saved: Vec<u32> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, vec![4]);
+        let files = fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(files, 1, "the temporary file was left behind");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn settings_are_written_in_the_background() {
+        let path = temp_path();
+        let store = SettingsStore::load(&path);
+        let guild_id = GuildId::new(1);
+        store.update(guild_id, |settings| settings.music_volume = 50);
+        store.update(guild_id, |settings| settings.duck_level = 10);
+
+        let mut saved = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let reloaded = SettingsStore::load(&path).get(guild_id);
+            if reloaded.duck_level == 10 {
+                saved = Some(reloaded);
+                break;
+            }
+        }
+        let saved = saved.expect("the settings were never written");
+        assert_eq!((saved.music_volume, saved.duck_level), (50, 10));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
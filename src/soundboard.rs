@@ -1,16 +1,105 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
 
 use futures::stream;
 use serenity::all::{
-    ButtonStyle, ChannelId, Context, CreateInteractionResponse, CreateMessage, GuildId, UserId,
+    ButtonStyle, ChannelId, Context, CreateInteractionResponse, CreateInteractionResponseMessage,
+    CreateMessage, GuildId, UserId,
 };
+use serenity::async_trait;
 use serenity::builder::CreateButton;
 use serenity::futures::StreamExt;
+use songbird::events::{Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent};
+use songbird::tracks::TrackHandle;
 
-use crate::{general, Data, AUDIO_PATH};
+use crate::{general, player::PlayerHandle, Data, AUDIO_PATH};
+
+/// Each clip with the gain bringing it to the target loudness.
+type Playing = Arc<Mutex<HashMap<GuildId, Vec<(TrackHandle, f32)>>>>;
+
+/// Soundboard clips playing in each guild, so they can be changed while they play.
+#[derive(Clone, Default)]
+pub struct Clips {
+    playing: Playing,
+}
+
+/// Forgets a clip once it ends, the music is raised back after the last one.
+struct ClipEndNotifier {
+    clips: Clips,
+    guild_id: GuildId,
+    player: PlayerHandle,
+}
+
+#[async_trait]
+impl VoiceEventHandler for ClipEndNotifier {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(track_list) = ctx {
+            let mut playing = self.clips.playing.lock().unwrap();
+            if let Some(handles) = playing.get_mut(&self.guild_id) {
+                handles.retain(|(handle, _)| {
+                    track_list
+                        .iter()
+                        .all(|(_, ended)| ended.uuid() != handle.uuid())
+                });
+                if handles.is_empty() {
+                    self.player.unduck();
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Clips {
+    /// Keeps track of a clip, the music of `player` is ducked until the last clip ends.
+    /// `gain` is kept across volume changes.
+    pub fn add(&self, guild_id: GuildId, handle: TrackHandle, gain: f32, player: PlayerHandle) {
+        let _ = handle.add_event(
+            Event::Track(TrackEvent::End),
+            ClipEndNotifier {
+                clips: self.clone(),
+                guild_id,
+                player: player.clone(),
+            },
+        );
+
+        let mut playing = self.playing.lock().unwrap();
+        let handles = playing.entry(guild_id).or_default();
+        if handles.is_empty() {
+            player.duck();
+        }
+        handles.push((handle, gain));
+    }
+
+    /// Stops the clips of the guild, the music goes on.
+    pub fn stop(&self, guild_id: GuildId) {
+        if let Some(handles) = self.playing.lock().unwrap().get(&guild_id) {
+            for (handle, _) in handles {
+                let _ = handle.stop();
+            }
+        }
+    }
+
+    /// Stops and forgets the clips of a guild the bot is leaving, their end is never reported.
+    pub fn clear(&self, guild_id: GuildId) {
+        if let Some(handles) = self.playing.lock().unwrap().remove(&guild_id) {
+            for (handle, _) in handles {
+                let _ = handle.stop();
+            }
+        }
+    }
+
+    pub fn set_volume(&self, guild_id: GuildId, volume: f32) {
+        if let Some(handles) = self.playing.lock().unwrap().get(&guild_id) {
+            for (handle, gain) in handles {
+                let _ = handle.set_volume(volume * gain);
+            }
+        }
+    }
+}
 
 pub fn get_soundboard_data(location: &str) -> Result<Vec<(String, String, String)>, io::Error> {
     let path = Path::new(location);
@@ -60,6 +149,8 @@ pub async fn soundboard_handler(
 
     // Listen the combined stream to get interactions.
     while let Some(interaction) = combined_stream.next().await {
+        let mut response = CreateInteractionResponse::Acknowledge;
+
         if let Some((_, _, found_path)) = data
             .soundboard_data
             .iter()
@@ -67,24 +158,32 @@ pub async fn soundboard_handler(
         {
             general::join_channel(ctx, &guild_id, author_id, data).await;
             let path = PathBuf::from(AUDIO_PATH.to_owned() + found_path);
-            general::play_from_source(ctx, &guild_id, path).await;
+            general::play_from_source(ctx, &guild_id, path, data).await;
 
-            let mut last_interaction = data.last_interaction.lock().await;
-            *last_interaction = Instant::now();
+            data.inactivity.touch(guild_id);
         } else if interaction.data.custom_id == "stop" {
-            general::stop_reproduction(ctx, &guild_id, data).await;
+            data.clips.stop(guild_id);
+        } else if interaction.data.custom_id == "stop_all" {
+            // Stopping the music empties the queue of everyone listening.
+            let is_admin = interaction
+                .member
+                .as_ref()
+                .and_then(|member| member.permissions)
+                .is_some_and(|permissions| permissions.administrator());
+            if is_admin {
+                general::stop_reproduction(ctx, &guild_id, data).await;
+            } else {
+                response = CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .content("Only administrators can stop the music.")
+                        .ephemeral(true),
+                );
+            }
         } else if interaction.data.custom_id == "quit" {
-            let manager = songbird::get(ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialisation")
-                .clone();
-            let _ = manager.leave(guild_id).await;
+            data.inactivity.quit(ctx, guild_id).await;
         }
 
-        interaction
-            .create_response(&ctx, CreateInteractionResponse::Acknowledge)
-            .await
-            .unwrap();
+        interaction.create_response(&ctx, response).await.unwrap();
     }
 
     general::delete_messages(ctx, voice_channel_id).await;
@@ -125,6 +224,11 @@ pub async fn send_soundboard_msg(
             .label("STOP")
             .style(ButtonStyle::Danger),
     );
+    msg = msg.button(
+        CreateButton::new("stop_all")
+            .label("STOP ALL")
+            .style(ButtonStyle::Danger),
+    );
     msg = msg.button(
         CreateButton::new("quit")
             .label("QUIT")
@@ -1,54 +1,1139 @@
+use std::{env, fmt, future::Future, time::Duration};
+
 use rspotify::{
-    model::{AlbumId, TrackId},
-    prelude::BaseClient,
-    ClientCredsSpotify,
+    http::HttpError,
+    model::{
+        AlbumId, AlbumType, ArtistId, Country, EpisodeId, FullTrack, Market, Page, PlayableItem,
+        PlaylistId, ShowId, SimplifiedEpisode, TrackId,
+    },
+    prelude::{BaseClient, Id},
+    ClientCredsSpotify, ClientError, ClientResult, Config, Credentials,
+};
+use songbird::input::{AudioStreamError, AuxMetadata};
+use tokio::sync::Mutex;
+
+use crate::{
+    general::{PlaylistPage, PlaylistRange},
+    resolver::YoutubeBackend,
 };
 
-use crate::Data;
+// Spotify caps most paginated endpoints at 50 items per page.
+const PAGE_SIZE: u32 = 50;
+const ALBUMS_PER_REQUEST: usize = 20;
+
+// YouTube results compared for every Spotify track.
+const MATCH_CANDIDATES: usize = 5;
+const ISRC_CANDIDATES: usize = 2;
+const ISRC_BONUS: f32 = 15.0;
+// Below this the search by ISRC is tried as well.
+const CONFIDENT_SCORE: f32 = 70.0;
+
+const UNWANTED_KEYWORDS: [&str; 14] = [
+    "live",
+    "cover",
+    "sped up",
+    "slowed",
+    "reverb",
+    "nightcore",
+    "karaoke",
+    "instrumental",
+    "remix",
+    "8d",
+    "1 hour",
+    "10 hours",
+    "loop",
+    "reaction",
+];
+
+/// What we know of a Spotify track to find it on YouTube.
+#[derive(Clone, Debug)]
+pub struct SpotifyTrack {
+    /// Spotify track ID, missing for local files in playlists.
+    pub id: Option<String>,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub duration: Duration,
+    pub isrc: Option<String>,
+}
+
+impl SpotifyTrack {
+    /// "Artists - Name", shown until the YouTube match is known.
+    pub fn display_title(&self) -> String {
+        if self.artists.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} - {}", self.artists.join(", "), self.name)
+        }
+    }
+
+    pub fn search_query(&self) -> String {
+        match self.artists.first() {
+            Some(artist) => format!("{} {}", self.name, artist),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl SpotifyTrack {
+    /// Podcast episodes are searched by the show name in place of the artist.
+    fn from_episode(episode: SimplifiedEpisode, show_name: &str) -> Self {
+        SpotifyTrack {
+            id: Some(episode.id.id().to_string()),
+            name: episode.name,
+            artists: vec![show_name.to_string()],
+            duration: episode.duration.to_std().unwrap_or_default(),
+            isrc: None,
+        }
+    }
+}
+
+impl From<FullTrack> for SpotifyTrack {
+    fn from(track: FullTrack) -> Self {
+        SpotifyTrack {
+            id: track.id.map(|id| id.id().to_string()),
+            name: track.name,
+            artists: track
+                .artists
+                .into_iter()
+                .map(|artist| artist.name)
+                .collect(),
+            duration: track.duration.to_std().unwrap_or_default(),
+            isrc: track.external_ids.get("isrc").cloned(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    InvalidId(String),
+    Auth(String),
+    NotFound,
+    Api(String),
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpotifyError::InvalidId(id) => write!(f, "`{}` is not a valid Spotify link.", id),
+            SpotifyError::Auth(e) => write!(f, "Could not log in to Spotify ({}).", e),
+            SpotifyError::NotFound => {
+                write!(f, "Spotify could not find it, is it public?")
+            }
+            SpotifyError::Api(e) => write!(f, "Spotify request failed ({}).", e),
+        }
+    }
+}
+
+impl std::error::Error for SpotifyError {}
+
+impl From<ClientError> for SpotifyError {
+    fn from(e: ClientError) -> Self {
+        match status_code(&e) {
+            Some(404) | Some(400) => SpotifyError::NotFound,
+            Some(code) => SpotifyError::Api(format!("status code {}", code)),
+            None => SpotifyError::Api(e.to_string()),
+        }
+    }
+}
+
+/// Client credentials Spotify client that keeps its token fresh.
+///
+/// The token is refreshed before a call when it is about to expire, and once more
+/// if Spotify still answers 401. Setting `SPOTIFY_AUTH_URL` / `SPOTIFY_API_URL`
+/// points the client to another server, e.g. a local mock of the token endpoint.
+pub struct SpotifyClient {
+    client: ClientCredsSpotify,
+    refresh_lock: Mutex<()>,
+    /// Country used where Spotify needs one, e.g. top tracks and podcasts.
+    market: Market,
+}
+
+impl SpotifyClient {
+    pub fn new(creds: Credentials, config: Config) -> Self {
+        SpotifyClient {
+            client: ClientCredsSpotify::with_config(
+                creds,
+                Config {
+                    // Refreshing is done here, so it can be logged and retried on 401.
+                    token_refreshing: false,
+                    ..config
+                },
+            ),
+            refresh_lock: Mutex::new(()),
+            market: Market::Country(Country::UnitedStates),
+        }
+    }
+
+    pub fn from_env(creds: Credentials) -> Self {
+        let mut config = Config::default();
+        if let Ok(auth_url) = env::var("SPOTIFY_AUTH_URL") {
+            config.auth_base_url = auth_url;
+        }
+        if let Ok(api_url) = env::var("SPOTIFY_API_URL") {
+            config.api_base_url = api_url;
+        }
+
+        let mut client = SpotifyClient::new(creds, config);
+        if let Ok(code) = env::var("SPOTIFY_MARKET") {
+            match serde_json::from_value(serde_json::Value::String(code.to_uppercase())) {
+                Ok(country) => client.market = Market::Country(country),
+                Err(_) => eprintln!("Ignoring unknown SPOTIFY_MARKET '{}'", code),
+            }
+        }
+        client
+    }
+
+    /// Requests a new token, whatever the state of the current one.
+    pub async fn refresh_token(&self) -> Result<(), SpotifyError> {
+        let _guard = self.refresh_lock.lock().await;
+        self.request_token().await
+    }
+
+    async fn request_token(&self) -> Result<(), SpotifyError> {
+        println!("Refreshing Spotify token");
+
+        match self.client.request_token().await {
+            Ok(()) => {
+                println!("Spotify token refreshed");
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Spotify token refresh failed: {}", e);
+                Err(SpotifyError::Auth(e.to_string()))
+            }
+        }
+    }
+
+    async fn ensure_token(&self) -> Result<(), SpotifyError> {
+        let _guard = self.refresh_lock.lock().await;
+
+        // Whoever held the lock before us may have refreshed it already.
+        if self.token_expired().await {
+            self.request_token().await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn token_expired(&self) -> bool {
+        self.client
+            .token
+            .lock()
+            .await
+            .unwrap()
+            .as_ref()
+            .is_none_or(|token| token.is_expired())
+    }
+
+    /// Runs `request` with a valid token, retrying once with a new token on 401.
+    async fn call<T, F, Fut>(&self, request: F) -> Result<T, SpotifyError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        self.ensure_token().await?;
+
+        match request().await {
+            Err(e) if status_code(&e) == Some(401) => {
+                println!("Spotify answered 401, retrying with a new token");
+                self.refresh_token().await?;
+                Ok(request().await?)
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+fn status_code(e: &ClientError) -> Option<u16> {
+    match e {
+        ClientError::Http(http_error) => match http_error.as_ref() {
+            HttpError::StatusCode(response) => Some(response.status().as_u16()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
 pub async fn get_urls_album(
     passed_album_id: String,
-    spotify_client: &ClientCredsSpotify,
-) -> Vec<String> {
-    match AlbumId::from_id(&passed_album_id) {
-        Ok(album_id) => {
-            let mut urls = Vec::new();
-            let items = spotify_client.album(album_id, None);
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+    let album_id = AlbumId::from_id(&passed_album_id)
+        .map_err(|_| SpotifyError::InvalidId(passed_album_id.clone()))?;
+
+    let page = album_track_ids(&album_id, range, spotify).await?;
+
+    Ok(PlaylistPage {
+        tracks: full_tracks(&page.tracks, spotify).await?,
+        total: page.total,
+    })
+}
 
-            for track in items.await.expect("msg").tracks.items {
-                urls.push(track.name + " " + &track.artists[0].name);
+/// The artist's top tracks, or every track of their albums and singles with `range.discography`.
+pub async fn get_urls_artist(
+    passed_artist_id: String,
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+    let artist_id = ArtistId::from_id(&passed_artist_id)
+        .map_err(|_| SpotifyError::InvalidId(passed_artist_id.clone()))?;
+    println!("Spotify artist found: {:?}", artist_id);
+
+    if range.discography {
+        return get_artist_discography(&artist_id, range, spotify).await;
+    }
+
+    let top_tracks = spotify
+        .call(|| {
+            spotify
+                .client
+                .artist_top_tracks(artist_id.clone(), Some(spotify.market))
+        })
+        .await?;
+    let total = top_tracks.len();
+
+    Ok(PlaylistPage {
+        tracks: top_tracks
+            .into_iter()
+            .skip(range.offset)
+            .take(range.limit)
+            .map(SpotifyTrack::from)
+            .collect(),
+        total,
+    })
+}
+
+async fn get_artist_discography(
+    artist_id: &ArtistId<'_>,
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+    let album_ids = collect_pages(
+        PlaylistRange::everything(),
+        |offset| {
+            spotify.call(move || {
+                spotify.client.artist_albums_manual(
+                    artist_id.clone(),
+                    [AlbumType::Album, AlbumType::Single],
+                    Some(spotify.market),
+                    Some(PAGE_SIZE),
+                    Some(offset),
+                )
+            })
+        },
+        |album| album.id,
+    )
+    .await?
+    .tracks;
+
+    // Track counts only come with the full albums, which also carry their first tracks.
+    let range_end = range.offset.saturating_add(range.limit);
+    let mut track_ids = Vec::new();
+    let mut total = 0;
+    for ids in album_ids.chunks(ALBUMS_PER_REQUEST) {
+        let albums = spotify
+            .call(|| {
+                spotify
+                    .client
+                    .albums(ids.iter().cloned(), Some(spotify.market))
+            })
+            .await?;
+
+        for album in albums {
+            let album_total = album.tracks.total as usize;
+            let start = range.offset.saturating_sub(total);
+            let end = range_end.saturating_sub(total).min(album_total);
+
+            if start < end && end <= album.tracks.items.len() {
+                track_ids.extend(
+                    album.tracks.items[start..end]
+                        .iter()
+                        .filter_map(|track| track.id.clone()),
+                );
+            } else if start < end {
+                let part = PlaylistRange {
+                    offset: start,
+                    limit: end - start,
+                    discography: false,
+                };
+                track_ids.extend(album_track_ids(&album.id, part, spotify).await?.tracks);
             }
+            total += album_total;
+        }
+    }
+
+    Ok(PlaylistPage {
+        tracks: full_tracks(&track_ids, spotify).await?,
+        total,
+    })
+}
+
+async fn album_track_ids(
+    album_id: &AlbumId<'_>,
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<TrackId<'static>>, SpotifyError> {
+    collect_pages(
+        range,
+        |offset| {
+            spotify.call(move || {
+                spotify.client.album_track_manual(
+                    album_id.clone(),
+                    None,
+                    Some(PAGE_SIZE),
+                    Some(offset),
+                )
+            })
+        },
+        |track| track.id,
+    )
+    .await
+}
+
+/// Album listings leave out the ISRC, the full tracks have it.
+async fn full_tracks(
+    ids: &[TrackId<'static>],
+    spotify: &SpotifyClient,
+) -> Result<Vec<SpotifyTrack>, SpotifyError> {
+    let mut tracks = Vec::with_capacity(ids.len());
+    for ids in ids.chunks(PAGE_SIZE as usize) {
+        let full_tracks = spotify
+            .call(|| spotify.client.tracks(ids.iter().cloned(), None))
+            .await?;
+        tracks.extend(full_tracks.into_iter().map(SpotifyTrack::from));
+    }
+    Ok(tracks)
+}
+
+/// Episodes of a podcast, newest first.
+pub async fn get_urls_show(
+    passed_show_id: String,
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+    let show_id = ShowId::from_id(&passed_show_id)
+        .map_err(|_| SpotifyError::InvalidId(passed_show_id.clone()))?;
+    println!("Spotify show found: {:?}", show_id);
+    let show_id = &show_id;
+
+    let show = spotify
+        .call(|| {
+            spotify
+                .client
+                .get_a_show(show_id.clone(), Some(spotify.market))
+        })
+        .await?;
+
+    collect_pages(
+        range,
+        |offset| {
+            spotify.call(move || {
+                spotify.client.get_shows_episodes_manual(
+                    show_id.clone(),
+                    Some(spotify.market),
+                    Some(PAGE_SIZE),
+                    Some(offset),
+                )
+            })
+        },
+        |episode| Some(SpotifyTrack::from_episode(episode, &show.name)),
+    )
+    .await
+}
+
+pub async fn get_urls_playlist(
+    passed_playlist_id: String,
+    range: PlaylistRange,
+    spotify: &SpotifyClient,
+) -> Result<PlaylistPage<SpotifyTrack>, SpotifyError> {
+    let playlist_id = PlaylistId::from_id(&passed_playlist_id)
+        .map_err(|_| SpotifyError::InvalidId(passed_playlist_id.clone()))?;
+    println!("Spotify playlist found: {:?}", playlist_id);
+    let playlist_id = &playlist_id;
+
+    collect_pages(
+        range,
+        |offset| {
+            spotify.call(move || {
+                spotify.client.playlist_items_manual(
+                    playlist_id.clone(),
+                    None,
+                    None,
+                    Some(PAGE_SIZE),
+                    Some(offset),
+                )
+            })
+        },
+        |playlist_item| match playlist_item.track {
+            Some(PlayableItem::Track(track)) => Some(SpotifyTrack::from(track)),
+            _ => None,
+        },
+    )
+    .await
+}
+
+/// Walks the pages of a Spotify listing from `range.offset` until `range.limit`
+/// items are collected or the listing ends.
+async fn collect_pages<T, U, F, Fut>(
+    range: PlaylistRange,
+    fetch_page: F,
+    convert: impl Fn(T) -> Option<U>,
+) -> Result<PlaylistPage<U>, SpotifyError>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<Page<T>, SpotifyError>>,
+{
+    let mut tracks = Vec::new();
+    let mut offset = range.offset as u32;
+
+    loop {
+        let page = fetch_page(offset).await?;
+        let total = page.total as usize;
+        let fetched = page.items.len() as u32;
+
+        tracks.extend(page.items.into_iter().filter_map(&convert));
+        offset += fetched;
+
+        if tracks.len() >= range.limit || page.next.is_none() || fetched == 0 {
+            tracks.truncate(range.limit);
+            return Ok(PlaylistPage { tracks, total });
+        }
+    }
+}
+
+/// What a Spotify link points to, with its ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpotifyLink<'a> {
+    Track(&'a str),
+    Album(&'a str),
+    Playlist(&'a str),
+    Artist(&'a str),
+    Show(&'a str),
+    Episode(&'a str),
+}
+
+/// Parses `https://open.spotify.com/[intl-xx/]<kind>/<id>` links and `spotify:<kind>:<id>` URIs.
+pub fn parse_link(link: &str) -> Option<SpotifyLink<'_>> {
+    let link = link.trim();
+    let (kind, id) = match link.strip_prefix("spotify:") {
+        Some(uri) => uri.split_once(':')?,
+        None => {
+            let path = link.split_once("spotify.com/")?.1;
+            let mut segments = path
+                .split(['?', '#'])
+                .next()?
+                .split('/')
+                .filter(|segment| !segment.is_empty() && !segment.starts_with("intl-"));
+            (segments.next()?, segments.next()?)
+        }
+    };
+
+    let id = id.split(['?', '#']).next()?;
+    if id.is_empty() {
+        return None;
+    }
+
+    match kind {
+        "track" => Some(SpotifyLink::Track(id)),
+        "album" => Some(SpotifyLink::Album(id)),
+        "playlist" => Some(SpotifyLink::Playlist(id)),
+        "artist" => Some(SpotifyLink::Artist(id)),
+        "show" => Some(SpotifyLink::Show(id)),
+        "episode" => Some(SpotifyLink::Episode(id)),
+        _ => None,
+    }
+}
+
+pub async fn get_spoti_track(
+    track_id: &str,
+    spotify: &SpotifyClient,
+) -> Result<SpotifyTrack, SpotifyError> {
+    let parsed_id =
+        TrackId::from_id(track_id).map_err(|_| SpotifyError::InvalidId(track_id.to_string()))?;
+
+    let track = spotify
+        .call(|| spotify.client.track(parsed_id.clone(), None))
+        .await?;
+
+    Ok(SpotifyTrack::from(track))
+}
+
+/// A podcast episode, to be looked up on YouTube like a track.
+pub async fn get_spoti_episode(
+    episode_id: &str,
+    spotify: &SpotifyClient,
+) -> Result<SpotifyTrack, SpotifyError> {
+    let parsed_id = EpisodeId::from_id(episode_id)
+        .map_err(|_| SpotifyError::InvalidId(episode_id.to_string()))?;
+
+    let episode = spotify
+        .call(|| {
+            spotify
+                .client
+                .get_an_episode(parsed_id.clone(), Some(spotify.market))
+        })
+        .await?;
+
+    Ok(SpotifyTrack {
+        id: Some(episode.id.id().to_string()),
+        name: episode.name,
+        artists: vec![episode.show.name],
+        duration: episode.duration.to_std().unwrap_or_default(),
+        isrc: None,
+    })
+}
 
-            urls
+/// Searches YouTube for `track` and returns the best scoring candidate.
+///
+/// When the plain search is not convincing, candidates found by ISRC get a chance too.
+pub async fn find_youtube_match(
+    track: &SpotifyTrack,
+    youtube: &dyn YoutubeBackend,
+) -> Result<AuxMetadata, AudioStreamError> {
+    let mut candidates = youtube
+        .search(&track.search_query(), MATCH_CANDIDATES)
+        .await?;
+    let mut isrc_hits = 0;
+
+    let confident =
+        best_candidate(track, &candidates, 0).is_some_and(|(_, score)| score >= CONFIDENT_SCORE);
+
+    if let (false, Some(isrc)) = (confident, &track.isrc) {
+        if let Ok(mut by_isrc) = youtube
+            .search(&format!("\"{}\"", isrc), ISRC_CANDIDATES)
+            .await
+        {
+            isrc_hits = by_isrc.len();
+            by_isrc.append(&mut candidates);
+            candidates = by_isrc;
         }
-        Err(e) => {
-            println!("Error on parsing spotify album: {:?}", e);
-            Vec::new()
+    }
+
+    let (best, score) = best_candidate(track, &candidates, isrc_hits).ok_or_else(|| {
+        AudioStreamError::Fail(format!("no YouTube match for '{}'", track.search_query()).into())
+    })?;
+
+    println!(
+        "Matched '{}' to '{}' ({}) with score {:.1}",
+        track.search_query(),
+        best.title.as_deref().unwrap_or("?"),
+        best.source_url.as_deref().unwrap_or("?"),
+        score
+    );
+
+    Ok(best.clone())
+}
+
+/// Picks the highest scoring candidate that has an URL.
+///
+/// The first `isrc_hits` candidates came from the ISRC search.
+pub fn best_candidate<'a>(
+    track: &SpotifyTrack,
+    candidates: &'a [AuxMetadata],
+    isrc_hits: usize,
+) -> Option<(&'a AuxMetadata, f32)> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.source_url.is_some())
+        .map(|(index, candidate)| {
+            let isrc_bonus = if index < isrc_hits { ISRC_BONUS } else { 0.0 };
+            (candidate, score_candidate(track, candidate) + isrc_bonus)
+        })
+        // `max_by` keeps the last of equal scores, ties go to the better ranked result.
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+/// How likely `candidate` is the same recording as `track`, higher is better.
+///
+/// Rewards a close duration, the artists and the title showing up, and official or
+/// "Topic" channels; penalizes live versions, covers, edits and loops unless the
+/// Spotify title asks for them.
+pub fn score_candidate(track: &SpotifyTrack, candidate: &AuxMetadata) -> f32 {
+    let title = normalize(candidate.title.as_deref().unwrap_or_default());
+    let channel = normalize(candidate.channel.as_deref().unwrap_or_default());
+    let track_name = normalize(&track.name);
+    let mut score = 0.0;
+
+    if let Some(duration) = candidate.duration {
+        let diff = duration.as_secs_f32() - track.duration.as_secs_f32();
+        let diff = diff.abs();
+        score += if diff <= 2.0 {
+            40.0
+        } else if diff <= 30.0 {
+            40.0 * (30.0 - diff) / 28.0
+        } else if diff <= 60.0 {
+            0.0
+        } else {
+            -30.0
+        };
+    }
+
+    for (index, artist) in track.artists.iter().enumerate() {
+        let artist = normalize(artist);
+        if artist.is_empty() {
+            continue;
+        }
+        if contains_words(&title, &artist) || contains_words(&channel, &artist) {
+            score += if index == 0 { 20.0 } else { 5.0 };
         }
     }
+
+    if contains_words(&title, &base_title(&track_name)) {
+        score += 20.0;
+    }
+
+    for keyword in UNWANTED_KEYWORDS {
+        if contains_words(&title, keyword) && !contains_words(&track_name, keyword) {
+            score -= 25.0;
+        }
+    }
+
+    if candidate
+        .channel
+        .as_deref()
+        .is_some_and(|channel| channel.ends_with(" - Topic"))
+    {
+        score += 15.0;
+    } else if channel.contains("vevo")
+        || channel.contains("official")
+        || contains_words(&title, "official audio")
+        || contains_words(&title, "official video")
+    {
+        score += 8.0;
+    }
+
+    score
 }
 
-pub fn get_spoti_playlist_id(url: &str) -> Option<&str> {
-    url.split("/playlist/").nth(1)?.split('?').next()
+/// Lowercase, alphanumeric words separated by single spaces.
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-pub fn get_spoti_album_id(url: &str) -> Option<&str> {
-    url.split("/album/").nth(1)?.split('?').next()
+fn contains_words(haystack: &str, needle: &str) -> bool {
+    !needle.is_empty() && format!(" {} ", haystack).contains(&format!(" {} ", needle))
 }
 
-pub fn get_spoti_track_id(url: &str) -> Option<&str> {
-    url.split("/track/").nth(1)?.split('?').next()
+/// Drops what usually follows the title itself, "song feat x" or "song remastered 2011".
+fn base_title(track_name: &str) -> String {
+    let mut words = Vec::new();
+    for word in track_name.split(' ') {
+        if matches!(word, "feat" | "ft" | "with" | "remastered" | "remaster") {
+            break;
+        }
+        words.push(word);
+    }
+    words.join(" ")
 }
 
-pub async fn get_spoti_track_title(track_id: &str, data: &Data) -> String {
-    let spoti_client = &data.spotify_client;
-    let parsed_id =
-        TrackId::from_id(track_id).expect("Could not convertid on single track spotify");
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use rspotify::http::Query;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Path and bearer token of every request the mock server got, in order.
+    type Requests = Arc<StdMutex<Vec<(String, Option<String>)>>>;
+
+    /// Serves `/api/token` with `token-1`, `token-2`... lasting `expires_in` seconds,
+    /// and `/v1/ping`, answering 401 to the tokens in `rejected`.
+    async fn mock_spotify(
+        expires_in: u64,
+        rejected: &'static [&'static str],
+    ) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let mut issued = 0;
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (path, token) = read_request(&mut socket).await;
+                seen.lock().unwrap().push((path.clone(), token.clone()));
+
+                let (status, body) = if path == "/api/token" {
+                    issued += 1;
+                    let body = format!(
+                        r#"{{"access_token":"token-{}","token_type":"Bearer","expires_in":{}}}"#,
+                        issued, expires_in
+                    );
+                    ("200 OK", body)
+                } else if token.is_some_and(|token| rejected.contains(&token.as_str())) {
+                    ("401 Unauthorized", String::from("{}"))
+                } else {
+                    ("200 OK", String::from("{}"))
+                };
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (base, requests)
+    }
+
+    async fn read_request(socket: &mut TcpStream) -> (String, Option<String>) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let head_len = loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+            assert!(read > 0, "connection closed mid request");
+        };
+
+        let head = String::from_utf8_lossy(&request[..head_len]).to_string();
+        let header = |name: &str| {
+            head.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        };
+        let body_len: usize = header("content-length").map_or(0, |len| len.parse().unwrap());
+        while request.len() < head_len + body_len {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        let path = head.split(' ').nth(1).unwrap().to_string();
+        let token = header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer ").map(str::to_string));
+        (path, token)
+    }
+
+    fn client(base: &str) -> SpotifyClient {
+        SpotifyClient::new(
+            Credentials::new("id", "secret"),
+            Config {
+                auth_base_url: format!("{}/", base),
+                api_base_url: format!("{}/v1/", base),
+                ..Config::default()
+            },
+        )
+    }
+
+    async fn ping(spotify: &SpotifyClient) -> Result<String, SpotifyError> {
+        let query = Query::new();
+        spotify
+            .call(|| spotify.client.api_get("ping", &query))
+            .await
+    }
+
+    fn request(path: &str, token: Option<&str>) -> (String, Option<String>) {
+        (path.to_string(), token.map(str::to_string))
+    }
+
+    #[test]
+    fn parses_every_kind_of_link() {
+        let cases = [
+            (
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                SpotifyLink::Track("4uLU6hMCjMI75M1A2tKUQC"),
+            ),
+            (
+                "https://open.spotify.com/album/1DFixLWuPkv3KT3TnV35m3",
+                SpotifyLink::Album("1DFixLWuPkv3KT3TnV35m3"),
+            ),
+            (
+                "https://open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M",
+                SpotifyLink::Playlist("37i9dQZF1DXcBWIGoYBM5M"),
+            ),
+            (
+                "https://open.spotify.com/artist/0OdUWJ0sBjDrqHygGUXeCF",
+                SpotifyLink::Artist("0OdUWJ0sBjDrqHygGUXeCF"),
+            ),
+            (
+                "https://open.spotify.com/show/5CfCWKI5pZ28U0uOzXkDHe",
+                SpotifyLink::Show("5CfCWKI5pZ28U0uOzXkDHe"),
+            ),
+            (
+                "https://open.spotify.com/episode/512ojhOuo1ktJprKbVcKyQ",
+                SpotifyLink::Episode("512ojhOuo1ktJprKbVcKyQ"),
+            ),
+        ];
+
+        for (link, expected) in cases {
+            assert_eq!(parse_link(link), Some(expected), "{}", link);
+        }
+    }
+
+    #[test]
+    fn parses_link_variants() {
+        let cases = [
+            "https://open.spotify.com/intl-it/track/abc123",
+            "https://open.spotify.com/intl-pt-BR/track/abc123",
+            "https://open.spotify.com/track/abc123?si=d0f7a7c5e4b24c0c",
+            "https://open.spotify.com/track/abc123?si=xyz&context=spotify%3Aalbum",
+            "https://open.spotify.com/track/abc123#section",
+            "open.spotify.com/track/abc123/",
+            "  https://open.spotify.com/track/abc123  ",
+            "spotify:track:abc123",
+            "spotify:track:abc123?si=xyz",
+        ];
+
+        for link in cases {
+            assert_eq!(
+                parse_link(link),
+                Some(SpotifyLink::Track("abc123")),
+                "{}",
+                link
+            );
+        }
+        assert_eq!(
+            parse_link("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+            Some(SpotifyLink::Playlist("37i9dQZF1DXcBWIGoYBM5M"))
+        );
+    }
+
+    #[test]
+    fn rejects_other_links() {
+        let cases = [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://open.spotify.com/",
+            "https://open.spotify.com/track/",
+            "https://open.spotify.com/track/?si=xyz",
+            "https://open.spotify.com/user/someone",
+            "https://open.spotify.com/intl-it/",
+            "spotify:track:",
+            "spotify:user:someone",
+            "spotify:track",
+            "never gonna give you up",
+        ];
+
+        for link in cases {
+            assert_eq!(parse_link(link), None, "{}", link);
+        }
+    }
+
+    fn track(name: &str) -> SpotifyTrack {
+        SpotifyTrack {
+            id: None,
+            name: name.to_string(),
+            artists: vec![String::from("Artist")],
+            duration: Duration::from_secs(200),
+            isrc: None,
+        }
+    }
+
+    fn candidate(title: &str, channel: &str, secs: Option<u64>, url: &str) -> AuxMetadata {
+        AuxMetadata {
+            title: Some(title.to_string()),
+            channel: Some(channel.to_string()),
+            duration: secs.map(Duration::from_secs),
+            source_url: (!url.is_empty()).then(|| url.to_string()),
+            ..AuxMetadata::default()
+        }
+    }
 
-    let track = spoti_client
-        .track(parsed_id, None)
-        .await
-        .expect("Error on get_spoti_track_title");
+    #[test]
+    fn unwanted_versions_are_penalized() {
+        let cases = [
+            ("Song", "Artist - Song (Live at Wembley)", 25.0),
+            ("Song", "Artist - Song (Acoustic Cover)", 25.0),
+            ("Song", "Artist - Song [Remix]", 25.0),
+            ("Song", "Artist - Song sped up + reverb", 50.0),
+        ];
 
-    track.name + " " + &track.artists[0].name
+        for (name, title, penalty) in cases {
+            let track = track(name);
+            let clean = score_candidate(&track, &candidate("Artist - Song", "Uploads", None, "a"));
+            let scored = score_candidate(&track, &candidate(title, "Uploads", None, "a"));
+            assert_eq!(clean - scored, penalty, "{}", title);
+        }
+    }
+
+    #[test]
+    fn versions_asked_for_are_not_penalized() {
+        // 20 for the artist and 20 for the title, minus 25 when the version was not asked for.
+        let cases = [
+            ("Song (Live)", "Artist - Song (Live)", 40.0),
+            ("Song", "Artist - Song (Live)", 15.0),
+            ("Song - Remix", "Artist - Song Remix", 40.0),
+            ("Song", "Artist - Song Remix", 15.0),
+        ];
+
+        for (name, title, expected) in cases {
+            let scored = score_candidate(&track(name), &candidate(title, "Uploads", None, "a"));
+            assert_eq!(scored, expected, "{} / {}", name, title);
+        }
+    }
+
+    #[test]
+    fn official_channels_get_a_bonus() {
+        let cases = [
+            ("Records - Topic", 15.0),
+            ("RecordsVEVO", 8.0),
+            ("Records Official", 8.0),
+            ("Records", 0.0),
+        ];
+
+        let track = track("Song");
+        let plain = score_candidate(&track, &candidate("Song", "Uploads", None, "a"));
+        for (channel, bonus) in cases {
+            let scored = score_candidate(&track, &candidate("Song", channel, None, "a"));
+            assert_eq!(scored - plain, bonus, "{}", channel);
+        }
+    }
+
+    #[test]
+    fn duration_difference_bands() {
+        let cases = [
+            (200, 40.0),
+            (202, 40.0),
+            (198, 40.0),
+            (216, 20.0),
+            (230, 0.0),
+            (245, 0.0),
+            (260, 0.0),
+            (261, -30.0),
+            (100, -30.0),
+        ];
+
+        let track = track("Song");
+        let unknown = score_candidate(&track, &candidate("Song", "Uploads", None, "a"));
+        for (secs, points) in cases {
+            let scored = score_candidate(&track, &candidate("Song", "Uploads", Some(secs), "a"));
+            assert_eq!(scored - unknown, points, "{}s", secs);
+        }
+    }
+
+    #[test]
+    fn best_candidate_picks_the_highest_score() {
+        let track = track("Song");
+        let candidates = [
+            candidate("Artist - Song (Live)", "Uploads", Some(200), "live"),
+            candidate("Artist - Song", "Artist - Topic", Some(201), "topic"),
+            candidate("Artist - Song", "Uploads", Some(260), "long"),
+        ];
+
+        let (best, _) = best_candidate(&track, &candidates, 0).unwrap();
+        assert_eq!(best.source_url.as_deref(), Some("topic"));
+    }
+
+    #[test]
+    fn best_candidate_ties_go_to_the_first() {
+        let track = track("Song");
+        let candidates = [
+            candidate("Artist - Song", "Uploads", Some(200), "first"),
+            candidate("Artist - Song", "Uploads", Some(200), "second"),
+        ];
+
+        let (best, _) = best_candidate(&track, &candidates, 0).unwrap();
+        assert_eq!(best.source_url.as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn best_candidate_adds_the_isrc_bonus() {
+        let track = track("Song");
+        let candidates = [
+            candidate("Song", "Uploads", Some(200), "isrc"),
+            candidate("Song", "Records VEVO", Some(200), "search"),
+        ];
+
+        let (best, score) = best_candidate(&track, &candidates, 1).unwrap();
+        assert_eq!(best.source_url.as_deref(), Some("isrc"));
+        assert_eq!(score, score_candidate(&track, &candidates[0]) + ISRC_BONUS);
+    }
+
+    #[test]
+    fn best_candidate_skips_results_without_url() {
+        let track = track("Song");
+        let candidates = [
+            candidate("Artist - Song", "Artist - Topic", Some(200), ""),
+            candidate("Song cover", "Uploads", None, "cover"),
+        ];
+
+        let (best, _) = best_candidate(&track, &candidates, 0).unwrap();
+        assert_eq!(best.source_url.as_deref(), Some("cover"));
+        assert!(best_candidate(&track, &candidates[..1], 0).is_none());
+        assert!(best_candidate(&track, &[], 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn valid_token_is_reused() {
+        let (base, requests) = mock_spotify(3600, &[]).await;
+        let spotify = client(&base);
+
+        ping(&spotify).await.unwrap();
+        ping(&spotify).await.unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                request("/api/token", None),
+                request("/v1/ping", Some("token-1")),
+                request("/v1/ping", Some("token-1")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refreshed_before_the_call() {
+        let (base, requests) = mock_spotify(0, &[]).await;
+        let spotify = client(&base);
+
+        ping(&spotify).await.unwrap();
+        ping(&spotify).await.unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                request("/api/token", None),
+                request("/v1/ping", Some("token-1")),
+                request("/api/token", None),
+                request("/v1/ping", Some("token-2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_refreshed_on_401() {
+        let (base, requests) = mock_spotify(3600, &["token-1"]).await;
+        let spotify = client(&base);
+
+        ping(&spotify).await.unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                request("/api/token", None),
+                request("/v1/ping", Some("token-1")),
+                request("/api/token", None),
+                request("/v1/ping", Some("token-2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn second_401_is_reported() {
+        let (base, requests) = mock_spotify(3600, &["token-1", "token-2"]).await;
+        let spotify = client(&base);
+
+        let result = ping(&spotify).await;
+
+        assert!(matches!(result, Err(SpotifyError::Api(e)) if e == "status code 401"));
+        assert_eq!(requests.lock().unwrap().len(), 4);
+    }
 }